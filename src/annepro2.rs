@@ -1,13 +1,41 @@
-use hidapi::{HidApi, HidDevice, HidResult};
-use std::{intrinsics::transmute, thread, time::Duration};
-
-const ANNEPRO2_VID: u16 = 0x04d9;
-
-const PID_C15: u16 = 0x8008;
-const PID_C18: u16 = 0x8009;
-
+//! The IAP protocol spoken by the Anne Pro 2 bootloader, and flash sessions
+//! built on top of it.
+
+use crate::cancel::CancelToken;
+use crate::checksum::crc32;
+use crate::device::{self, AP2Device, Mode, Revision, Selector};
+use crate::error::{AP2FlashError, Result};
+use crate::events::{FlashEvent, Observer};
+use crate::firmware::{self, Segment};
+use crate::plan::FlashPlan;
+use crate::record::{to_hex, Recorder};
+use crate::response::Response;
+use crate::session::AnnePro2;
+use crate::transport::Transport;
+use crate::version::FwVersion;
+use hidapi::HidApi;
+use log::{debug, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{cell::Cell, ops::Range, path::PathBuf, str::FromStr, thread, time::Duration};
+
+/// How long to wait for the device to answer a command.
+const READ_TIMEOUT_MS: i32 = 5000;
+
+/// `IapMode` argument that reboots into the bootloader, also reported by
+/// `IapGetMode` while in IAP mode.
+pub const MODE_IAP: u8 = 1;
+/// `IapMode` argument that jumps to the application.
+pub const MODE_APP: u8 = 2;
+
+/// Set once an unrecognised reply has been warned about, so a bootloader
+/// that answers every command that way gets one warning per session instead
+/// of one per chunk. Cleared when a plan starts running.
+static UNRECOGNISED_REPLY_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// An addressable node on the keyboard's internal bus.
 #[repr(u8)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum AP2Target {
     UsbHost = 1,
     BleHost = 2,
@@ -16,6 +44,26 @@ pub enum AP2Target {
     McuBle = 5,
 }
 
+impl FromStr for AP2Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("main") {
+            Ok(AP2Target::McuMain)
+        } else if s.eq_ignore_ascii_case("led") {
+            Ok(AP2Target::McuLed)
+        } else if s.eq_ignore_ascii_case("ble") {
+            Ok(AP2Target::McuBle)
+        } else {
+            Err(format!(
+                "invalid target {:?}, choose from main, led, and ble",
+                s
+            ))
+        }
+    }
+}
+
+/// The command class, first byte of every payload.
 #[repr(u8)]
 #[derive(Debug, Copy, Clone)]
 pub enum L2Command {
@@ -27,6 +75,7 @@ pub enum L2Command {
     BLE = 64,
 }
 
+/// The command within [`L2Command::FW`], second byte of every payload.
 #[repr(u8)]
 #[derive(Debug, Copy, Clone)]
 pub enum KeyCommand {
@@ -38,130 +87,580 @@ pub enum KeyCommand {
     // 0x31
     IapWriteApFlag = 50,
     // 0x32
+    /// Not sent by the stock updater and unconfirmed on real hardware; the
+    /// opcode is a guess next to `IapWirteMemory`. Only used to verify, and
+    /// any failure is taken to mean the bootloader lacks it.
+    IapReadMemory = 51,
+    // 0x33
     IapEraseMemory = 67, // 0x43
+    /// Unconfirmed like `IapReadMemory`, guessed next to `IapEraseMemory`.
+    IapGetChecksum = 68, // 0x44
 }
 
+impl AP2Target {
+    /// Decodes the address nibble used in command frames.
+    pub fn from_u8(value: u8) -> Option<AP2Target> {
+        match value {
+            1 => Some(AP2Target::UsbHost),
+            2 => Some(AP2Target::BleHost),
+            3 => Some(AP2Target::McuMain),
+            4 => Some(AP2Target::McuLed),
+            5 => Some(AP2Target::McuBle),
+            _ => None,
+        }
+    }
+}
+
+impl KeyCommand {
+    /// Decodes the key command byte of a payload.
+    pub fn from_u8(value: u8) -> Option<KeyCommand> {
+        match value {
+            0 => Some(KeyCommand::Reserved),
+            1 => Some(KeyCommand::IapMode),
+            2 => Some(KeyCommand::IapGetMode),
+            3 => Some(KeyCommand::IapGetFwVersion),
+            49 => Some(KeyCommand::IapWirteMemory),
+            50 => Some(KeyCommand::IapWriteApFlag),
+            51 => Some(KeyCommand::IapReadMemory),
+            67 => Some(KeyCommand::IapEraseMemory),
+            68 => Some(KeyCommand::IapGetChecksum),
+            _ => None,
+        }
+    }
+}
+
+/// How a failed chunk write is retried before flashing is aborted.
 #[derive(Debug, Copy, Clone)]
-pub enum AP2FlashError {
-    NoDeviceFound,
-    MultipleDeviceFound,
-    USBError,
-    EraseError,
-    FlashError,
-    OtherError,
+pub struct RetryPolicy {
+    /// Attempts made after the first one fails.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every further retry.
+    pub backoff: Duration,
 }
 
-pub fn flash_firmware<R: std::io::Read>(
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 3,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+/// How a flash session behaves, independent of the images written. Start
+/// from [`FlashOptions::default`] and change the fields that matter.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct FlashOptions {
+    /// Jump to the new firmware once it is written.
+    pub boot: bool,
+    /// Read the image back and compare it before setting the AP flag.
+    /// Experimental: the read-back and checksum opcodes are guesses, see
+    /// [`KeyCommand::IapReadMemory`].
+    pub verify: bool,
+    /// Erase the whole application region instead of only the pages the
+    /// image covers.
+    pub full_erase: bool,
+    pub retry: RetryPolicy,
+    /// How long to wait for a keyboard in IAP mode to be plugged in.
+    pub wait: Duration,
+    /// Hardware revisions the images were built for. Empty allows any.
+    pub revisions: Vec<Revision>,
+    /// Log every report sent and received to this file.
+    pub record: Option<PathBuf>,
+}
+
+impl Default for FlashOptions {
+    fn default() -> Self {
+        FlashOptions {
+            boot: false,
+            verify: false,
+            full_erase: false,
+            retry: RetryPolicy::default(),
+            wait: Duration::from_secs(10),
+            revisions: Vec::new(),
+            record: None,
+        }
+    }
+}
+
+/// One MCU's worth of work in a flash session.
+#[derive(Debug, Clone)]
+pub struct FlashJob {
+    pub target: AP2Target,
+    pub segments: Vec<Segment>,
+}
+
+/// What was written to a single MCU.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct TargetReport {
+    pub target: AP2Target,
+    pub bytes: usize,
+    pub segments: usize,
+    pub verified: bool,
+}
+
+/// The outcome of a whole flash session.
+#[derive(Debug, Default)]
+#[non_exhaustive]
+pub struct BatchReport {
+    /// MCUs written completely, in the order they were flashed.
+    pub targets: Vec<TargetReport>,
+    /// Whether erasing has started, after which the old firmware can no
+    /// longer be relied on.
+    pub flash_modified: bool,
+    pub ap_flag_written: bool,
+    pub booted: bool,
+    /// Why the session stopped early. Jobs after the failing one were not
+    /// started and the AP flag was not written.
+    pub error: Option<AP2FlashError>,
+}
+
+impl BatchReport {
+    /// Whether every job was written and the AP flag set.
+    pub fn is_success(&self) -> bool {
+        self.error.is_none()
+    }
+
+    /// Whether the session stopped after erasing but before the AP flag was
+    /// written, leaving the keyboard stuck in IAP mode until it is flashed
+    /// again.
+    pub fn needs_reflash(&self) -> bool {
+        self.flash_modified && !self.ap_flag_written
+    }
+}
+
+/// Flashes a single image onto `target`, see [`flash_batch`].
+pub fn flash_firmware(
     target: AP2Target,
-    base: u32,
-    file: &mut R,
-    boot: bool,
-) -> std::result::Result<(), AP2FlashError> {
-    let api = HidApi::new().map_err(|_| AP2FlashError::USBError)?;
+    segments: &[Segment],
+    selector: &Selector,
+    options: &FlashOptions,
+    cancel: &CancelToken,
+) -> Result<()> {
+    let job = FlashJob {
+        target,
+        segments: segments.to_vec(),
+    };
+    match flash_batch(&[job], selector, options, &(), cancel).error {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
 
-    let (anne_devices, flash_device) = fetch_devices(&api);
+/// Flashes every job in order over a single device handle. The AP flag is
+/// only written, and the keyboard only booted, once all of them succeeded.
+/// Progress is reported to `observer` as it happens. Cancelling `cancel`
+/// stops the session before the next chunk; the AP flag is then not written.
+pub fn flash_batch(
+    jobs: &[FlashJob],
+    selector: &Selector,
+    options: &FlashOptions,
+    observer: &dyn Observer,
+    cancel: &CancelToken,
+) -> BatchReport {
+    let mut report = BatchReport::default();
+    if let Err(err) = run_batch(jobs, selector, options, observer, cancel, &mut report) {
+        report.error = Some(err);
+    }
+    report
+}
 
-    if !anne_devices.is_empty() && flash_device.is_none() {
-        println!("Please put your keyboard into IAP mode by disconnecting it and reconnecting it while holding the ESC key.");
+/// Runs `jobs` over an already opened `handle` to a keyboard of `revision`,
+/// e.g. an emulator for a dry run.
+pub fn flash_session<T: Transport>(
+    handle: &T,
+    revision: Revision,
+    jobs: &[FlashJob],
+    options: &FlashOptions,
+    observer: &dyn Observer,
+    cancel: &CancelToken,
+) -> BatchReport {
+    match FlashPlan::new(jobs, revision, options) {
+        Ok(plan) => plan.execute(handle, options, observer, cancel),
+        Err(err) => BatchReport {
+            error: Some(err),
+            ..BatchReport::default()
+        },
+    }
+}
 
-        let mut i = 10;
-        while i > 0 {
-            println!("Attempt in {} seconds.", i);
-            thread::sleep(Duration::from_secs(1));
-            i -= 1;
+/// Runs a plan, e.g. one saved earlier, on the keyboard picked by
+/// `selector`, which has to be of the revision the plan was made for. Only
+/// the retry, wait and record settings of `options` are used.
+pub fn flash_plan(
+    plan: &FlashPlan,
+    selector: &Selector,
+    options: &FlashOptions,
+    observer: &dyn Observer,
+    cancel: &CancelToken,
+) -> BatchReport {
+    let mut report = BatchReport::default();
+    if let Err(err) = run_plan(plan, selector, options, observer, cancel, &mut report) {
+        report.error = Some(err);
+    }
+    report
+}
+
+pub(crate) fn check_jobs(jobs: &[FlashJob]) -> Result<()> {
+    for job in jobs {
+        if job.segments.is_empty() {
+            return Err(AP2FlashError::InvalidImage(format!(
+                "image for {:?} is empty",
+                job.target
+            )));
         }
+        firmware::check_fits(&job.segments, job.target, None)?;
     }
+    Ok(())
+}
 
-    let (_, flash_device) = fetch_devices(&api);
-
-    let dev = flash_device.expect("No device found.");
-
-    let handle = api.open_path(dev.path()).expect("unable to open device");
-    handle.set_blocking_mode(true).expect("non-blocking");
-    println!(
-        "device is {:?}",
-        handle.get_product_string().expect("string")
-    );
-
-    // Flashing Code
-    erase_device(&handle, target, base).map_err(|err| {
-        println!("Error while erasing: {}", err);
-        AP2FlashError::USBError
-    })?;
-    flash_file(&handle, target, base, file);
-    write_ap_flag(&handle, 2).map_err(|e| {
-        println!("Error while writing AP flag: {:?}", e);
-        AP2FlashError::USBError
-    })?;
-    if boot {
-        boot_device(&handle).map_err(|e| {
-            println!("Error while booting device: {:?}", e);
-            AP2FlashError::USBError
-        })?;
+/// Refuses images built for another revision, or too large for its MCUs.
+pub(crate) fn check_revision(
+    jobs: &[FlashJob],
+    revision: Revision,
+    options: &FlashOptions,
+) -> Result<()> {
+    if !options.revisions.is_empty() && !options.revisions.contains(&revision) {
+        return Err(AP2FlashError::UnsupportedRevision {
+            found: revision,
+            supported: options.revisions.clone(),
+        });
+    }
+    for job in jobs {
+        firmware::check_fits(&job.segments, job.target, Some(revision))?;
     }
     Ok(())
 }
 
-fn fetch_devices(api: &HidApi) -> (Vec<&hidapi::DeviceInfo>, Option<&hidapi::DeviceInfo>) {
-    for dev in api.device_list() {
-        println!(
-            "HID Dev: {:04x}:{:04x} {}",
-            dev.vendor_id(),
-            dev.product_id(),
-            dev.product_string()
-                .map(|it| format!("({:})", it.replace("\n", " - ")))
-                .unwrap_or_default()
-        );
+fn run_batch(
+    jobs: &[FlashJob],
+    selector: &Selector,
+    options: &FlashOptions,
+    observer: &dyn Observer,
+    cancel: &CancelToken,
+    report: &mut BatchReport,
+) -> Result<()> {
+    check_jobs(jobs)?;
+    let (api, dev) = wait_for_keyboard(selector, options, cancel)?;
+    let plan = FlashPlan::new(jobs, dev.revision, options)?;
+    run_on_device(&api, &dev, &plan, options, observer, cancel, report)
+}
+
+fn run_plan(
+    plan: &FlashPlan,
+    selector: &Selector,
+    options: &FlashOptions,
+    observer: &dyn Observer,
+    cancel: &CancelToken,
+    report: &mut BatchReport,
+) -> Result<()> {
+    plan.validate()?;
+    let (api, dev) = wait_for_keyboard(selector, options, cancel)?;
+    if dev.revision != plan.revision {
+        return Err(AP2FlashError::UnsupportedRevision {
+            found: dev.revision,
+            supported: vec![plan.revision],
+        });
     }
+    run_on_device(&api, &dev, plan, options, observer, cancel, report)
+}
 
-    let anne_devices = api
-        .device_list()
-        .filter(|dev| dev.vendor_id() == ANNEPRO2_VID)
-        .collect::<Vec<_>>();
+fn wait_for_keyboard(
+    selector: &Selector,
+    options: &FlashOptions,
+    cancel: &CancelToken,
+) -> Result<(HidApi, AP2Device)> {
+    let mut api = HidApi::new()?;
 
-    let flash_device = anne_devices.iter().find(|dev| {
-        (dev.product_id() == PID_C15 && dev.interface_number() == 1)
-            || (dev.product_id() == PID_C18)
-    });
-    (anne_devices.clone(), flash_device.cloned())
+    if !device::enumerate(&api).iter().any(AP2Device::is_flashable) {
+        info!("Please put your keyboard into IAP mode by disconnecting it and reconnecting it while holding the ESC key.");
+        info!("Waiting up to {} seconds...", options.wait.as_secs());
+    }
+    let dev = device::wait_for_device(&mut api, selector, Mode::Iap, options.wait, cancel)?;
+    Ok((api, dev))
+}
+
+fn run_on_device(
+    api: &HidApi,
+    dev: &AP2Device,
+    plan: &FlashPlan,
+    options: &FlashOptions,
+    observer: &dyn Observer,
+    cancel: &CancelToken,
+    report: &mut BatchReport,
+) -> Result<()> {
+    let session = dev.open(api)?;
+    info!("device is {:?}", session.handle().get_product_string()?);
+    observer.on_event(&FlashEvent::DeviceFound(dev.clone()));
+
+    run_recorded(session.handle(), plan, options, observer, cancel, report)
 }
 
-pub fn write_ap_flag(handle: &HidDevice, flag: u8) -> HidResult<()> {
+/// Runs the plan, logging every report to `options.record` if set.
+pub(crate) fn run_recorded<T: Transport>(
+    handle: &T,
+    plan: &FlashPlan,
+    options: &FlashOptions,
+    observer: &dyn Observer,
+    cancel: &CancelToken,
+    report: &mut BatchReport,
+) -> Result<()> {
+    match &options.record {
+        Some(path) => {
+            let recorder = Recorder::create(path, handle)?;
+            let session = AnnePro2::new(recorder, plan.revision, Mode::Iap)
+                .with_retry_policy(options.retry)
+                .with_cancel_token(cancel.clone());
+            plan.run(&session, observer, report)
+        }
+        None => {
+            let session = AnnePro2::new(handle, plan.revision, Mode::Iap)
+                .with_retry_policy(options.retry)
+                .with_cancel_token(cancel.clone());
+            plan.run(&session, observer, report)
+        }
+    }
+}
+
+/// Opens the keyboard in IAP mode picked by `selector`.
+pub fn open_device(api: &HidApi, selector: &Selector) -> Result<AnnePro2> {
+    let dev = device::select(&device::enumerate(api), selector, Some(Mode::Iap))?;
+    dev.open(api)
+}
+
+/// Reboots the keyboard picked by `selector` into IAP mode and waits up to
+/// `timeout` for the bootloader to enumerate. A keyboard that is already in
+/// IAP mode is returned straight away.
+///
+/// The bootloader shows up under a new path. While it is the only keyboard
+/// connected, whatever bootloader appears is taken. Otherwise it is found
+/// again by its serial number, although nothing confirms the bootloader
+/// reports the same one as the application, and a keyboard without one
+/// cannot be switched at all.
+pub fn switch_to_iap(
+    api: &mut HidApi,
+    selector: &Selector,
+    timeout: Duration,
+    cancel: &CancelToken,
+) -> Result<AP2Device> {
+    let devices = device::enumerate(api);
+    let dev = device::select(&devices, selector, None)?;
+    if dev.mode == Mode::Iap {
+        return Ok(dev);
+    }
+    let keyboards = devices
+        .iter()
+        .filter(|it| it.is_command_interface())
+        .count();
+    if dev.serial.is_none() && keyboards > 1 {
+        return Err(AP2FlashError::NoSerialNumber { path: dev.path });
+    }
+
+    let handle = api.open_path(dev.info().path())?;
+    enter_iap_mode(&handle)?;
+    drop(handle);
+
+    let selector = if keyboards > 1 {
+        Selector {
+            serial: dev.serial.clone(),
+            ..Selector::default()
+        }
+    } else {
+        Selector::default()
+    };
+    device::wait_for_device(api, &selector, Mode::Iap, timeout, cancel)
+}
+
+/// Asks the main MCU which mode it is running in.
+pub fn get_mode<T: Transport>(handle: &T) -> Result<Mode> {
+    let buffer: Vec<u8> = vec![L2Command::FW as u8, KeyCommand::IapGetMode as u8];
+    let payload = query(handle, AP2Target::McuMain, &buffer)?;
+    match payload.first() {
+        Some(&MODE_IAP) => Ok(Mode::Iap),
+        Some(&MODE_APP) => Ok(Mode::Normal),
+        _ => Err(AP2FlashError::UnexpectedResponse {
+            command: KeyCommand::IapGetMode,
+            target: AP2Target::McuMain,
+            address: None,
+            report: payload,
+        }),
+    }
+}
+
+/// Asks `target` for its bootloader and application firmware versions.
+pub fn get_fw_version<T: Transport>(handle: &T, target: AP2Target) -> Result<FwVersion> {
+    let buffer: Vec<u8> = vec![L2Command::FW as u8, KeyCommand::IapGetFwVersion as u8];
+    let payload = query(handle, target, &buffer)?;
+    FwVersion::parse(target, &payload).ok_or(AP2FlashError::UnexpectedResponse {
+        command: KeyCommand::IapGetFwVersion,
+        target,
+        address: None,
+        report: payload,
+    })
+}
+
+/// Sets the flag the bootloader checks to decide whether the application
+/// is valid and may be started.
+pub fn write_ap_flag<T: Transport>(handle: &T, flag: u8) -> Result<()> {
     let buffer: Vec<u8> = vec![L2Command::FW as u8, KeyCommand::IapWriteApFlag as u8, flag];
     write_to_target(handle, AP2Target::McuMain, &buffer)?;
     Ok(())
 }
 
-pub fn flash_file<F: std::io::Read>(
-    handle: &HidDevice,
+/// Writes `image` to flash starting at `base`, retrying each chunk according
+/// to `retry`. Stops at the first chunk that still fails once retries are
+/// exhausted, or once `cancel` is cancelled; nothing after it is written.
+/// Every chunk written and every retry is reported to `observer`.
+pub fn flash_file<T: Transport>(
+    handle: &T,
+    target: AP2Target,
+    base: u32,
+    image: &[u8],
+    retry: &RetryPolicy,
+    observer: &dyn Observer,
+    cancel: &CancelToken,
+) -> Result<()> {
+    let chunk_size = chunk_size(target);
+    let mut current_addr = base;
+    for chunk in image.chunks(chunk_size) {
+        cancel.check()?;
+        // The last chunk is sent as it is rather than padded to a full
+        // chunk, which could run past the end of flash or clobber whatever
+        // is written right after it.
+        write_chunk_with_retry(handle, target, current_addr, chunk, retry, observer)?;
+        current_addr += chunk.len() as u32;
+        let written = (current_addr - base) as usize;
+        debug!(
+            "Wrote {} bytes, at {:#08x}, total: {} bytes written",
+            chunk.len(),
+            current_addr - chunk.len() as u32,
+            written
+        );
+        observer.on_event(&FlashEvent::ChunkWritten {
+            target,
+            address: current_addr - chunk.len() as u32,
+            len: chunk.len(),
+            written,
+            total: image.len(),
+        });
+    }
+    Ok(())
+}
+
+fn write_chunk_with_retry<T: Transport>(
+    handle: &T,
+    target: AP2Target,
+    addr: u32,
+    chunk: &[u8],
+    retry: &RetryPolicy,
+    observer: &dyn Observer,
+) -> Result<()> {
+    let mut delay = retry.backoff;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match write_chunk(handle, target, addr, chunk) {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt > retry.retries => {
+                return Err(AP2FlashError::WriteFailed {
+                    target,
+                    address: addr,
+                    attempts: attempt,
+                    source: Box::new(err),
+                });
+            }
+            Err(err) => {
+                warn!(
+                    "Error \"{}\" occurred during write at {:#08x}, retrying in {:?}...",
+                    err, addr, delay
+                );
+                observer.on_event(&FlashEvent::Retry {
+                    target,
+                    address: addr,
+                    attempt,
+                    delay,
+                    error: err.to_string(),
+                });
+                thread::sleep(delay);
+                delay *= 2;
+            }
+        }
+    }
+}
+
+/// Reads flash back and compares it against `file`, which must be the image
+/// that was written at `base`, failing with the first mismatching address.
+///
+/// Memory is read back directly if the bootloader supports it, otherwise each
+/// chunk is compared by CRC-32 and the start of the bad chunk is reported.
+pub fn verify_file<T: Transport, F: std::io::Read>(
+    handle: &T,
     target: AP2Target,
     base: u32,
     file: &mut F,
-) {
-    let chunk_size = match &target {
-        AP2Target::McuBle => 32usize,
-        _ => 48usize,
-    };
+) -> Result<()> {
+    verify_file_with(handle, target, base, file, &Cell::new(true))
+}
+
+/// Like [`verify_file`], but skips the read-back attempt if `can_read` is
+/// already cleared, and clears it if the bootloader turns out not to
+/// support reading. Fails with [`AP2FlashError::VerifyUnsupported`] if the
+/// checksum query is not supported either.
+pub(crate) fn verify_file_with<T: Transport, F: std::io::Read>(
+    handle: &T,
+    target: AP2Target,
+    base: u32,
+    file: &mut F,
+    can_read: &Cell<bool>,
+) -> Result<()> {
+    let chunk_size = chunk_size(target);
     let mut current_addr = base;
     loop {
         let mut buffer = vec![0u8; chunk_size];
-        let size = file.read(&mut buffer).expect("read file failure");
+        let size = file.read(&mut buffer)?;
 
         if size > 0 {
-            let result = write_chunk(handle, target, current_addr, &buffer);
-            if result.is_err() {
-                println!(
-                    "[WARNING] Error {:?} occurred during write at {:#08x}, continuing...",
-                    result.unwrap_err(),
-                    current_addr
-                );
-            } else {
-                println!(
-                    "[INFO] Wrote {} bytes, at {:#08x}, total: {} bytes written",
-                    size,
-                    current_addr,
-                    (current_addr + size as u32) - base
-                );
+            let expected = &buffer[..size];
+            if can_read.get() {
+                match read_memory(handle, target, current_addr, size) {
+                    Ok(actual) => {
+                        if let Some(offset) = actual.iter().zip(expected).position(|(a, b)| a != b)
+                        {
+                            return Err(AP2FlashError::VerifyMismatch {
+                                target,
+                                address: current_addr + offset as u32,
+                            });
+                        }
+                    }
+                    Err(err) if is_unsupported(&err) => {
+                        info!(
+                            "Bootloader cannot read memory ({}), comparing checksums",
+                            err
+                        );
+                        can_read.set(false);
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            if !can_read.get() {
+                let crc = match read_checksum(handle, target, current_addr, size as u32) {
+                    Ok(crc) => crc,
+                    Err(err) if is_unsupported(&err) => {
+                        return Err(AP2FlashError::VerifyUnsupported {
+                            target,
+                            source: Box::new(err),
+                        })
+                    }
+                    Err(err) => return Err(err),
+                };
+                if crc != crc32(expected) {
+                    return Err(AP2FlashError::VerifyMismatch {
+                        target,
+                        address: current_addr,
+                    });
+                }
             }
             current_addr += size as u32;
         }
@@ -170,46 +669,248 @@ pub fn flash_file<F: std::io::Read>(
             break;
         }
     }
+    Ok(())
+}
+
+/// Whether `err` suggests the bootloader does not implement the command,
+/// rather than that the connection failed. As the read-back commands are
+/// unconfirmed, a missing or odd answer counts as well as a rejection.
+fn is_unsupported(err: &AP2FlashError) -> bool {
+    matches!(
+        err,
+        AP2FlashError::NoResponse { .. }
+            | AP2FlashError::UnexpectedResponse { .. }
+            | AP2FlashError::Rejected { .. }
+    )
+}
+
+pub(crate) fn chunk_size(target: AP2Target) -> usize {
+    match target {
+        AP2Target::McuBle => 32usize,
+        _ => 48usize,
+    }
+}
+
+/// Reads `len` bytes of flash at `addr`, at most one chunk.
+pub fn read_memory<T: Transport>(
+    handle: &T,
+    target: AP2Target,
+    addr: u32,
+    len: usize,
+) -> Result<Vec<u8>> {
+    let mut buffer: Vec<u8> = vec![L2Command::FW as u8, KeyCommand::IapReadMemory as u8];
+    buffer.extend_from_slice(&addr.to_le_bytes());
+    buffer.push(len as u8);
+    let mut payload = query(handle, target, &buffer)?;
+    if payload.len() < len {
+        return Err(AP2FlashError::UnexpectedResponse {
+            command: KeyCommand::IapReadMemory,
+            target,
+            address: Some(addr),
+            report: payload,
+        });
+    }
+    payload.truncate(len);
+    Ok(payload)
 }
 
-pub fn write_chunk(
-    handle: &HidDevice,
+/// Asks the bootloader for the CRC-32 of `len` bytes of flash at `addr`.
+pub fn read_checksum<T: Transport>(
+    handle: &T,
+    target: AP2Target,
+    addr: u32,
+    len: u32,
+) -> Result<u32> {
+    let mut buffer: Vec<u8> = vec![L2Command::FW as u8, KeyCommand::IapGetChecksum as u8];
+    buffer.extend_from_slice(&addr.to_le_bytes());
+    buffer.extend_from_slice(&len.to_le_bytes());
+    let payload = query(handle, target, &buffer)?;
+    match payload.get(..4) {
+        Some(crc) => Ok(u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]])),
+        None => Err(AP2FlashError::UnexpectedResponse {
+            command: KeyCommand::IapGetChecksum,
+            target,
+            address: Some(addr),
+            report: payload,
+        }),
+    }
+}
+
+/// Writes one chunk at `addr`: at most 32 bytes for the BLE MCU and 48 for
+/// the others.
+pub fn write_chunk<T: Transport>(
+    handle: &T,
     target: AP2Target,
     addr: u32,
     chunk: &[u8],
-) -> HidResult<()> {
+) -> Result<()> {
     let mut buffer: Vec<u8> = vec![L2Command::FW as u8, KeyCommand::IapWirteMemory as u8];
-    let addr_slice: [u8; 4] = unsafe { transmute(addr.to_le()) };
-    buffer.extend_from_slice(&addr_slice);
+    buffer.extend_from_slice(&addr.to_le_bytes());
     buffer.extend_from_slice(chunk);
-    write_to_target(handle, target, &buffer).map(|_| ())
+    write_to_target(handle, target, &buffer)
 }
 
-pub fn erase_device(handle: &HidDevice, target: AP2Target, addr: u32) -> HidResult<()> {
+/// Erases `range` one page at a time. Every page must be answered by the
+/// device before the next one is sent, and no further page is sent once
+/// `cancel` is cancelled. Only an answer that echoes the erase back with a
+/// failure status fails it; the reply layout is still unconfirmed.
+pub fn erase_range<T: Transport>(
+    handle: &T,
+    target: AP2Target,
+    range: Range<u32>,
+    page_size: u32,
+    cancel: &CancelToken,
+) -> Result<()> {
+    for addr in range.step_by(page_size as usize) {
+        cancel.check()?;
+        erase_device(handle, target, addr)?;
+    }
+    Ok(())
+}
+
+/// Erases the page starting at `addr`.
+pub fn erase_device<T: Transport>(handle: &T, target: AP2Target, addr: u32) -> Result<()> {
     let mut buffer: Vec<u8> = vec![L2Command::FW as u8, KeyCommand::IapEraseMemory as u8];
-    let addr_slice: [u8; 4] = unsafe { transmute(addr.to_le()) };
-    buffer.extend_from_slice(&addr_slice);
+    buffer.extend_from_slice(&addr.to_le_bytes());
 
     write_to_target(handle, target, &buffer)?;
     Ok(())
 }
 
-pub fn boot_device(handle: &HidDevice) -> HidResult<()> {
+/// Leaves the bootloader and starts the application. The keyboard resets
+/// right away and never answers.
+pub fn boot_device<T: Transport>(handle: &T) -> Result<()> {
+    send_mode(handle, MODE_APP)
+}
+
+/// Tells a keyboard running its normal firmware to reboot into IAP mode.
+/// As with booting, the keyboard resets right away and never answers.
+pub fn enter_iap_mode<T: Transport>(handle: &T) -> Result<()> {
+    send_mode(handle, MODE_IAP)
+}
+
+fn send_mode<T: Transport>(handle: &T, mode: u8) -> Result<()> {
     let buffer: Vec<u8> = vec![
-        0x00, 0x7b, 0x10, 0x31, 0x10, 0x03, 0x00, 0x00, 0x7d, 0x02, 0x01, 0x02,
+        0x00, 0x7b, 0x10, 0x31, 0x10, 0x03, 0x00, 0x00, 0x7d, 0x02, 0x01, mode,
     ];
 
     // directly use write because we shouldn't pad this command to 64 bytes
-    let lol = handle.write(&buffer);
+    handle
+        .write_report(&buffer)
+        .map_err(|source| AP2FlashError::Transport {
+            command: KeyCommand::IapMode,
+            target: AP2Target::McuMain,
+            address: None,
+            source,
+        })?;
 
-    if lol.is_err() {
-        println!("err: {:?}", lol.unwrap_err());
-    }
+    Ok(())
+}
 
+/// Sends a command that only acts, like erasing or writing, to `target`.
+///
+/// The reply layout has not been checked against a capture of a real
+/// keyboard, so only a reply that echoes the command back is trusted, and
+/// fails the command if its status is not `STATUS_OK`. Any other reply is
+/// taken to mean the command was carried out, as the tool always did: a
+/// rejected command then goes unnoticed. The first such reply of a session
+/// is warned about, later ones are only logged at debug level.
+pub(crate) fn write_to_target<T: Transport>(
+    handle: &T,
+    target: AP2Target,
+    payload: &[u8],
+) -> Result<()> {
+    let report = exchange(handle, target, payload)?;
+    if recognise(target, payload, &report)?.is_none() {
+        let command = command_of(payload).0;
+        if UNRECOGNISED_REPLY_WARNED.swap(true, Ordering::Relaxed) {
+            debug!(
+                "Unrecognised reply to {:?} from {:?}: {}",
+                command,
+                target,
+                to_hex(&report)
+            );
+        } else {
+            warn!(
+                "Unrecognised reply to {:?} from {:?}, assuming it and any other command \
+                 answered this way were carried out; failures cannot be detected: {}",
+                command,
+                target,
+                to_hex(&report)
+            );
+        }
+    }
     Ok(())
 }
 
-pub fn write_to_target(handle: &HidDevice, target: AP2Target, payload: &[u8]) -> HidResult<usize> {
+/// Makes the next unrecognised reply warn again, see [`write_to_target`].
+pub(crate) fn reset_unrecognised_reply_warning() {
+    UNRECOGNISED_REPLY_WARNED.store(false, Ordering::Relaxed);
+}
+
+/// Sends a command whose answer is needed to `target` and returns the
+/// payload of its reply. Unlike [`write_to_target`], a reply that is not
+/// recognised fails with [`AP2FlashError::UnexpectedResponse`].
+fn query<T: Transport>(handle: &T, target: AP2Target, payload: &[u8]) -> Result<Vec<u8>> {
+    let report = exchange(handle, target, payload)?;
+    match recognise(target, payload, &report)? {
+        Some(response) => Ok(response.payload),
+        None => {
+            let (command, address) = command_of(payload);
+            Err(AP2FlashError::UnexpectedResponse {
+                command,
+                target,
+                address,
+                report,
+            })
+        }
+    }
+}
+
+/// The command in `payload` and, for commands that take one, its address.
+fn command_of(payload: &[u8]) -> (KeyCommand, Option<u32>) {
+    let command = payload
+        .get(1)
+        .and_then(|&it| KeyCommand::from_u8(it))
+        .unwrap_or(KeyCommand::Reserved);
+    let address = match command {
+        KeyCommand::IapWirteMemory
+        | KeyCommand::IapReadMemory
+        | KeyCommand::IapEraseMemory
+        | KeyCommand::IapGetChecksum => payload
+            .get(2..6)
+            .map(|it| u32::from_le_bytes([it[0], it[1], it[2], it[3]])),
+        _ => None,
+    };
+    (command, address)
+}
+
+/// Decodes `report` as the reply to `payload` sent to `target`: `None` if it
+/// does not echo the target and command back, an error if it does but
+/// carries a failure status.
+fn recognise(target: AP2Target, payload: &[u8], report: &[u8]) -> Result<Option<Response>> {
+    let response = Response::parse(report).filter(|it| {
+        it.source == target as u8
+            && payload.first() == Some(&it.l2_command)
+            && payload.get(1) == Some(&it.key_command)
+    });
+    match response {
+        Some(response) if !response.is_success() => {
+            let (command, address) = command_of(payload);
+            Err(AP2FlashError::Rejected {
+                command,
+                target,
+                address,
+                status: response.status,
+            })
+        }
+        response => Ok(response),
+    }
+}
+
+/// Frames `payload` for `target`, sends it and returns the raw reply.
+fn exchange<T: Transport>(handle: &T, target: AP2Target, payload: &[u8]) -> Result<Vec<u8>> {
+    let (command, address) = command_of(payload);
     let mut buffer: Vec<u8> = Vec::with_capacity(64);
     buffer.push(0x7b);
     buffer.push(0x10);
@@ -221,7 +922,11 @@ pub fn write_to_target(handle: &HidDevice, target: AP2Target, payload: &[u8]) ->
     buffer.push(0x7d);
     buffer.extend_from_slice(payload);
     if buffer.len() > 64 {
-        panic!("Wut?");
+        return Err(AP2FlashError::PayloadTooLarge {
+            command,
+            target,
+            len: payload.len(),
+        });
     }
     // Pad to 64 bytes
     while buffer.len() < 64 {
@@ -230,20 +935,29 @@ pub fn write_to_target(handle: &HidDevice, target: AP2Target, payload: &[u8]) ->
 
     buffer.insert(0, 0); // First word is report id.
 
-    let lol = handle.write(&buffer);
-
-    if lol.is_err() {
-        let err = lol.as_ref().unwrap_err();
-        println!("err: {:?}", err);
-    }
+    let transport_error = |source| AP2FlashError::Transport {
+        command,
+        target,
+        address,
+        source,
+    };
+    handle.write_report(&buffer).map_err(transport_error)?;
 
     let mut buf: Vec<u8> = vec![0u8; 64];
-    if let Err(err) = handle.read(&mut buf) {
-        println!("err: {:?}", err);
-    };
+    let len = handle
+        .read_report(&mut buf, READ_TIMEOUT_MS)
+        .map_err(transport_error)?;
+    if len == 0 {
+        return Err(AP2FlashError::NoResponse {
+            command,
+            target,
+            address,
+        });
+    }
 
     use pretty_hex::*;
-    println!("read back: {:#?}", buf[0..].as_ref().hex_dump());
-
-    lol
+    trace!("sent: {:#?}", buffer.as_slice().hex_dump());
+    trace!("read back: {:#?}", buf[0..len].as_ref().hex_dump());
+    buf.truncate(len);
+    Ok(buf)
 }
@@ -0,0 +1,220 @@
+//! Single-file firmware bundles carrying the images for every MCU together
+//! with the metadata needed to flash them safely.
+//!
+//! A bundle is the magic `AP2BNDL\0`, the length of the JSON header as a
+//! little endian `u32`, the header itself and then the data of every
+//! segment, in the order the header lists them.
+
+use crate::annepro2::{AP2Target, FlashJob};
+use crate::checksum::crc32;
+use crate::device::Revision;
+use crate::error::{AP2FlashError, Result};
+use crate::firmware::{self, Segment};
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+/// First bytes of every bundle file.
+pub const MAGIC: &[u8; 8] = b"AP2BNDL\0";
+/// Header format written by this version of the tool.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The images of a bundle, in the order they are flashed.
+#[derive(Debug, Clone, Default)]
+pub struct Bundle {
+    /// Hardware revisions the images were built for. Empty means any.
+    pub revisions: Vec<Revision>,
+    pub images: Vec<Image>,
+}
+
+/// The image for one MCU.
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub target: AP2Target,
+    pub version: Option<String>,
+    pub segments: Vec<Segment>,
+}
+
+/// The JSON header at the start of a bundle.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Header {
+    pub format: u32,
+    pub revisions: Vec<Revision>,
+    pub images: Vec<ImageInfo>,
+}
+
+/// An image as described in the header.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageInfo {
+    pub target: AP2Target,
+    pub version: Option<String>,
+    pub segments: Vec<SegmentInfo>,
+}
+
+/// A segment as described in the header; its data follows the header.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SegmentInfo {
+    pub address: u32,
+    pub size: u32,
+    pub crc32: u32,
+}
+
+impl Bundle {
+    /// Whether the images may be flashed onto a keyboard of `revision`.
+    pub fn supports(&self, revision: Revision) -> bool {
+        self.revisions.is_empty() || self.revisions.contains(&revision)
+    }
+
+    /// The images as jobs for [`flash_batch`](crate::annepro2::flash_batch).
+    pub fn jobs(&self) -> Vec<FlashJob> {
+        self.images
+            .iter()
+            .map(|image| FlashJob {
+                target: image.target,
+                segments: image.segments.clone(),
+            })
+            .collect()
+    }
+
+    /// Describes the bundle without its data, as stored in the file.
+    pub fn header(&self) -> Header {
+        Header {
+            format: FORMAT_VERSION,
+            revisions: self.revisions.clone(),
+            images: self
+                .images
+                .iter()
+                .map(|image| ImageInfo {
+                    target: image.target,
+                    version: image.version.clone(),
+                    segments: image
+                        .segments
+                        .iter()
+                        .map(|segment| SegmentInfo {
+                            address: segment.address,
+                            size: segment.data.len() as u32,
+                            crc32: crc32(&segment.data),
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Checks every image fits its MCU and no MCU is listed twice.
+    pub fn validate(&self) -> Result<()> {
+        if self.images.is_empty() {
+            return Err(AP2FlashError::InvalidBundle("no images".into()));
+        }
+        for (i, image) in self.images.iter().enumerate() {
+            if self.images[..i].iter().any(|it| it.target == image.target) {
+                return Err(AP2FlashError::InvalidBundle(format!(
+                    "{:?} is listed more than once",
+                    image.target
+                )));
+            }
+            if image.segments.is_empty() {
+                return Err(AP2FlashError::InvalidBundle(format!(
+                    "image for {:?} is empty",
+                    image.target
+                )));
+            }
+            firmware::check_fits(&image.segments, image.target, None)?;
+        }
+        Ok(())
+    }
+
+    /// Encodes the bundle as stored on disk.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let header = serde_json::to_vec(&self.header()).expect("header is serializable");
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 4 + header.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&header);
+        for segment in self.images.iter().flat_map(|image| &image.segments) {
+            bytes.extend_from_slice(&segment.data);
+        }
+        bytes
+    }
+
+    /// Decodes a bundle, checking every segment against its CRC-32.
+    pub fn parse(bytes: &[u8]) -> Result<Bundle> {
+        let (header, mut data) = parse_header(bytes)?;
+
+        let mut images = Vec::with_capacity(header.images.len());
+        for info in header.images {
+            let mut segments = Vec::with_capacity(info.segments.len());
+            for segment in info.segments {
+                let size = segment.size as usize;
+                if data.len() < size {
+                    return Err(invalid("truncated image data"));
+                }
+                let (chunk, rest) = data.split_at(size);
+                data = rest;
+                if crc32(chunk) != segment.crc32 {
+                    return Err(AP2FlashError::InvalidBundle(format!(
+                        "checksum mismatch in {:?} image at {:#06x}",
+                        info.target, segment.address
+                    )));
+                }
+                segments.push(Segment {
+                    address: segment.address,
+                    data: chunk.to_vec(),
+                });
+            }
+            images.push(Image {
+                target: info.target,
+                version: info.version,
+                segments: firmware::normalize(segments)?,
+            });
+        }
+        if !data.is_empty() {
+            return Err(invalid("trailing data after the last image"));
+        }
+
+        let bundle = Bundle {
+            revisions: header.revisions,
+            images,
+        };
+        bundle.validate()?;
+        Ok(bundle)
+    }
+}
+
+/// Splits a bundle into its header and the data that follows it.
+pub fn parse_header(bytes: &[u8]) -> Result<(Header, &[u8])> {
+    if bytes.len() < MAGIC.len() + 4 || &bytes[..MAGIC.len()] != MAGIC {
+        return Err(invalid("not a firmware bundle"));
+    }
+    let len = u32::from_le_bytes(bytes[MAGIC.len()..MAGIC.len() + 4].try_into().unwrap()) as usize;
+    let rest = &bytes[MAGIC.len() + 4..];
+    if rest.len() < len {
+        return Err(invalid("truncated header"));
+    }
+    let header: Header = serde_json::from_slice(&rest[..len])
+        .map_err(|err| AP2FlashError::InvalidBundle(format!("bad header: {}", err)))?;
+    if header.format != FORMAT_VERSION {
+        return Err(AP2FlashError::InvalidBundle(format!(
+            "unsupported format version {}",
+            header.format
+        )));
+    }
+    Ok((header, &rest[len..]))
+}
+
+/// Reads and checks the bundle at `path`.
+pub fn read(path: &Path) -> Result<Bundle> {
+    Bundle::parse(&fs::read(path)?)
+}
+
+/// Validates `bundle` and writes it to `path`.
+pub fn write(path: &Path, bundle: &Bundle) -> Result<()> {
+    bundle.validate()?;
+    fs::write(path, bundle.to_bytes())?;
+    Ok(())
+}
+
+fn invalid(reason: &str) -> AP2FlashError {
+    AP2FlashError::InvalidBundle(reason.into())
+}
@@ -0,0 +1,37 @@
+//! Stopping long running operations from another thread.
+
+use crate::error::{AP2FlashError, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A flag shared between a long running operation and whoever may want to
+/// stop it. Clones refer to the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    /// Creates a token that is not cancelled yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks every holder of this token to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether [`cancel`](Self::cancel) was called on any clone.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Fails with [`AP2FlashError::Cancelled`] once cancelled, for use with
+    /// `?` between steps that must not be interrupted.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(AP2FlashError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,14 @@
+//! Checksums matching the ones computed by the bootloader.
+
+/// CRC-32 (IEEE 802.3), as used by the bootloader checksum query.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
@@ -0,0 +1,204 @@
+//! Discovery of Anne Pro 2 keyboards among the connected HID devices.
+
+use crate::cancel::CancelToken;
+use crate::error::{AP2FlashError, Result};
+use crate::session::AnnePro2;
+use hidapi::{DeviceInfo, HidApi};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// USB vendor id shared by every Anne Pro 2.
+pub const ANNEPRO2_VID: u16 = 0x04d9;
+
+const PID_C15: u16 = 0xa292;
+const PID_C18: u16 = 0xa293;
+const PID_C15_IAP: u16 = 0x8008;
+const PID_C18_IAP: u16 = 0x8009;
+
+/// How often to re-enumerate while waiting for a keyboard to show up.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Hardware revision, told apart by product id.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Revision {
+    C15,
+    C18,
+}
+
+/// What a keyboard is currently running.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Running the keyboard firmware.
+    Normal,
+    /// Sitting in the bootloader, ready to be flashed.
+    Iap,
+}
+
+impl fmt::Display for Revision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Revision::C15 => "C15",
+            Revision::C18 => "C18",
+        })
+    }
+}
+
+impl FromStr for Revision {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("c15") {
+            Ok(Revision::C15)
+        } else if s.eq_ignore_ascii_case("c18") {
+            Ok(Revision::C18)
+        } else {
+            Err(format!("invalid revision {:?}, choose from c15 and c18", s))
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Mode::Normal => "normal",
+            Mode::Iap => "iap",
+        })
+    }
+}
+
+/// One HID interface of a connected Anne Pro 2.
+#[derive(Debug, Clone, Serialize)]
+pub struct AP2Device {
+    pub revision: Revision,
+    pub mode: Mode,
+    pub interface: i32,
+    pub serial: Option<String>,
+    pub path: String,
+    /// USB device release number (bcdDevice) as "major.minor".
+    pub release: String,
+    #[serde(skip)]
+    info: DeviceInfo,
+}
+
+impl AP2Device {
+    fn from_info(info: &DeviceInfo) -> Option<AP2Device> {
+        if info.vendor_id() != ANNEPRO2_VID {
+            return None;
+        }
+        let (revision, mode) = match info.product_id() {
+            PID_C15 => (Revision::C15, Mode::Normal),
+            PID_C18 => (Revision::C18, Mode::Normal),
+            PID_C15_IAP => (Revision::C15, Mode::Iap),
+            PID_C18_IAP => (Revision::C18, Mode::Iap),
+            _ => return None,
+        };
+        let release = info.release_number();
+        Some(AP2Device {
+            revision,
+            mode,
+            interface: info.interface_number(),
+            serial: info.serial_number().map(str::to_owned),
+            path: info.path().to_string_lossy().into_owned(),
+            release: format!("{:x}.{:02x}", release >> 8, release & 0xff),
+            info: info.clone(),
+        })
+    }
+
+    /// Whether this is the interface that accepts commands.
+    pub fn is_command_interface(&self) -> bool {
+        // Everything but the C18 bootloader exposes its command endpoint on
+        // interface 1 only.
+        self.interface == 1 || (self.mode == Mode::Iap && self.revision == Revision::C18)
+    }
+
+    /// Whether this interface accepts IAP commands.
+    pub fn is_flashable(&self) -> bool {
+        self.mode == Mode::Iap && self.is_command_interface()
+    }
+
+    /// Opens this interface for sending commands.
+    pub fn open(&self, api: &HidApi) -> Result<AnnePro2> {
+        AnnePro2::open(api, self)
+    }
+
+    /// The raw hidapi description of this interface.
+    pub fn info(&self) -> &DeviceInfo {
+        &self.info
+    }
+}
+
+/// Narrows down which keyboard to use when several are connected. Unset
+/// fields match anything.
+#[derive(Debug, Clone, Default)]
+pub struct Selector {
+    pub serial: Option<String>,
+    pub path: Option<String>,
+    /// Position among the command interfaces, in the order `list` shows them.
+    pub index: Option<usize>,
+}
+
+impl Selector {
+    fn matches(&self, index: usize, dev: &AP2Device) -> bool {
+        self.serial
+            .as_ref()
+            .is_none_or(|serial| dev.serial.as_ref() == Some(serial))
+            && self.path.as_ref().is_none_or(|path| &dev.path == path)
+            && self.index.is_none_or(|it| it == index)
+    }
+}
+
+/// Picks the single command interface matching `selector`, optionally
+/// restricted to keyboards in `mode`.
+pub fn select(devices: &[AP2Device], selector: &Selector, mode: Option<Mode>) -> Result<AP2Device> {
+    let mut matches = devices
+        .iter()
+        .filter(|dev| dev.is_command_interface())
+        .enumerate()
+        .filter(|(index, dev)| {
+            selector.matches(*index, dev) && mode.is_none_or(|mode| dev.mode == mode)
+        })
+        .map(|(_, dev)| dev);
+    match (matches.next(), matches.next()) {
+        (Some(dev), None) => Ok(dev.clone()),
+        (Some(_), Some(_)) => Err(AP2FlashError::MultipleDeviceFound { mode }),
+        (None, _) => Err(AP2FlashError::NoDeviceFound { mode }),
+    }
+}
+
+/// Returns every HID interface that belongs to an Anne Pro 2, in either mode.
+pub fn enumerate(api: &HidApi) -> Vec<AP2Device> {
+    let mut devices = api
+        .device_list()
+        .filter_map(AP2Device::from_info)
+        .collect::<Vec<_>>();
+    devices.sort_by(|a, b| a.path.cmp(&b.path));
+    devices
+}
+
+/// Re-enumerates until a keyboard in `mode` matching `selector` shows up,
+/// failing with [`AP2FlashError::NoDeviceFound`] once `timeout` has passed.
+pub fn wait_for_device(
+    api: &mut HidApi,
+    selector: &Selector,
+    mode: Mode,
+    timeout: Duration,
+    cancel: &CancelToken,
+) -> Result<AP2Device> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if cancel.is_cancelled() {
+            return Err(AP2FlashError::Cancelled);
+        }
+        api.refresh_devices()?;
+        match select(&enumerate(api), selector, Some(mode)) {
+            Err(AP2FlashError::NoDeviceFound { .. }) if Instant::now() < deadline => {
+                thread::sleep(POLL_INTERVAL)
+            }
+            result => return result,
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Running a flash session against the emulator while printing every report
+//! that would have gone to the keyboard.
+
+use crate::annepro2::{AP2Target, BatchReport, FlashOptions, KeyCommand, MODE_APP, MODE_IAP};
+use crate::cancel::CancelToken;
+use crate::emulator::Emulator;
+use crate::events::Observer;
+use crate::memory_map::MemoryMap;
+use crate::plan::FlashPlan;
+use crate::transport::Transport;
+use hidapi::{HidError, HidResult};
+use std::cell::{Cell, RefCell};
+use std::convert::TryInto;
+use std::io::Write;
+
+/// Wraps a transport and prints each report written to it, in hex and
+/// decoded, to `out` before passing it on.
+pub struct DryRun<T, W: Write> {
+    inner: T,
+    out: RefCell<W>,
+    sent: Cell<usize>,
+}
+
+impl<T: Transport, W: Write> DryRun<T, W> {
+    /// Prints everything written to `inner` to `out`.
+    pub fn new(inner: T, out: W) -> Self {
+        DryRun {
+            inner,
+            out: RefCell::new(out),
+            sent: Cell::new(0),
+        }
+    }
+
+    /// Number of reports written so far.
+    pub fn sent(&self) -> usize {
+        self.sent.get()
+    }
+
+    /// Gives back the sink everything was printed to.
+    pub fn into_output(self) -> W {
+        self.out.into_inner()
+    }
+}
+
+impl<T: Transport, W: Write> Transport for DryRun<T, W> {
+    fn write_report(&self, data: &[u8]) -> HidResult<usize> {
+        self.sent.set(self.sent.get() + 1);
+        let hex: Vec<String> = data.iter().map(|b| format!("{:02x}", b)).collect();
+        let mut out = self.out.borrow_mut();
+        writeln!(out, "#{:<5} {}", self.sent.get(), describe(data))
+            .and_then(|()| writeln!(out, "       {}", hex.join(" ")))
+            .map_err(|error| HidError::IoError { error })?;
+        self.inner.write_report(data)
+    }
+
+    fn read_report(&self, buf: &mut [u8], timeout_ms: i32) -> HidResult<usize> {
+        self.inner.read_report(buf, timeout_ms)
+    }
+}
+
+/// Runs `plan` on an emulated keyboard of the plan's revision, printing the
+/// report stream to `out` instead of touching a device. Stops between
+/// commands once `cancel` is cancelled.
+pub fn run<W: Write>(
+    plan: &FlashPlan,
+    options: &FlashOptions,
+    observer: &dyn Observer,
+    cancel: &CancelToken,
+    out: W,
+) -> BatchReport {
+    let mut emulator = Emulator::new();
+    for &target in &[AP2Target::McuMain, AP2Target::McuLed, AP2Target::McuBle] {
+        if let Some(map) = MemoryMap::of(target, plan.revision) {
+            emulator = emulator
+                .with_flash_size(target, map.flash_size as usize)
+                .with_page_size(map.page_size as usize);
+        }
+    }
+    let transport = DryRun::new(emulator, out);
+    let mut report = plan.execute(&transport, options, observer, cancel);
+    let sent = transport.sent();
+    if let Err(err) = writeln!(transport.into_output(), "{} reports", sent) {
+        report.error.get_or_insert(err.into());
+    }
+    report
+}
+
+/// Decodes a report as built by `write_to_target`, report id included.
+pub fn describe(report: &[u8]) -> String {
+    if report.len() < 11 || report[1] != 0x7b || report[8] != 0x7d {
+        return "not a command frame".into();
+    }
+    let target = match AP2Target::from_u8(report[3] >> 4) {
+        Some(target) => target,
+        None => return format!("unknown target {:#x}", report[3] >> 4),
+    };
+    let len = (report[5] as usize).clamp(2, report.len() - 9);
+    let payload = &report[9..9 + len];
+    let args = &payload[2..];
+    let address = args
+        .get(..4)
+        .map(|it| u32::from_le_bytes(it.try_into().unwrap()));
+
+    match (KeyCommand::from_u8(payload[1]), address) {
+        (Some(KeyCommand::IapEraseMemory), Some(addr)) => {
+            format!("erase {:?} page at {:#08x}", target, addr)
+        }
+        (Some(KeyCommand::IapWirteMemory), Some(addr)) => format!(
+            "write {} bytes to {:?} at {:#08x}",
+            args.len() - 4,
+            target,
+            addr
+        ),
+        (Some(KeyCommand::IapReadMemory), Some(addr)) => format!(
+            "read {} bytes from {:?} at {:#08x}",
+            args.get(4).copied().unwrap_or(0),
+            target,
+            addr
+        ),
+        (Some(KeyCommand::IapGetChecksum), Some(addr)) => format!(
+            "checksum {} bytes of {:?} at {:#08x}",
+            args.get(4..8)
+                .map_or(0, |it| u32::from_le_bytes(it.try_into().unwrap())),
+            target,
+            addr
+        ),
+        (Some(KeyCommand::IapWriteApFlag), _) => {
+            format!("set AP flag to {}", args.first().copied().unwrap_or(0))
+        }
+        (Some(KeyCommand::IapMode), _) => match args.first() {
+            Some(&MODE_APP) => "boot into the application".into(),
+            Some(&MODE_IAP) => "reboot into IAP mode".into(),
+            _ => "switch mode".into(),
+        },
+        (Some(command), _) => format!("{:?} to {:?}", command, target),
+        (None, _) => format!("unknown command {:#04x} to {:?}", payload[1], target),
+    }
+}
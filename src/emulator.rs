@@ -0,0 +1,299 @@
+//! An in-process stand-in for an Anne Pro 2 sitting in IAP mode.
+//!
+//! [`Emulator`] implements [`Transport`] and speaks the same framing as the
+//! real bootloader, so full flash sessions can be run and the resulting
+//! memory image inspected without a keyboard attached.
+
+use crate::annepro2::{AP2Target, KeyCommand, L2Command, MODE_APP, MODE_IAP};
+use crate::checksum::crc32;
+use crate::response::{STATUS_BAD_ADDRESS, STATUS_BAD_LENGTH, STATUS_OK, STATUS_UNSUPPORTED};
+use crate::transport::Transport;
+use crate::version::{FwVersion, Version};
+use hidapi::{HidError, HidResult};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+
+/// Flash size used for every MCU unless overridden.
+const DEFAULT_FLASH_SIZE: usize = 0x10000;
+/// Bytes cleared by one `IapEraseMemory`.
+const DEFAULT_PAGE_SIZE: usize = 0x400;
+
+const REPORT_SIZE: usize = 64;
+const HEADER_SIZE: usize = 8;
+
+/// The emulated keyboard; see the module documentation.
+pub struct Emulator {
+    state: RefCell<State>,
+}
+
+struct State {
+    flash: HashMap<AP2Target, Vec<u8>>,
+    page_size: usize,
+    versions: HashMap<AP2Target, FwVersion>,
+    ap_flag: Option<u8>,
+    booted: bool,
+    read_memory: bool,
+    checksum: bool,
+    replies: VecDeque<Vec<u8>>,
+}
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Emulator {
+    /// Creates an emulator with fully erased flash on every MCU.
+    pub fn new() -> Self {
+        let flash = [AP2Target::McuMain, AP2Target::McuLed, AP2Target::McuBle]
+            .iter()
+            .map(|&target| (target, vec![0xffu8; DEFAULT_FLASH_SIZE]))
+            .collect();
+        let versions = [AP2Target::McuMain, AP2Target::McuLed, AP2Target::McuBle]
+            .iter()
+            .map(|&target| {
+                let version = FwVersion {
+                    target,
+                    bootloader: Version::new(1, 0, 0),
+                    application: Version::new(0, 0, 0),
+                };
+                (target, version)
+            })
+            .collect();
+        Emulator {
+            state: RefCell::new(State {
+                flash,
+                page_size: DEFAULT_PAGE_SIZE,
+                versions,
+                ap_flag: None,
+                booted: false,
+                read_memory: true,
+                checksum: true,
+                replies: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Replaces the flash of `target` with `size` erased bytes.
+    pub fn with_flash_size(self, target: AP2Target, size: usize) -> Self {
+        self.state
+            .borrow_mut()
+            .flash
+            .insert(target, vec![0xffu8; size]);
+        self
+    }
+
+    /// Sets how many bytes a single erase command clears.
+    pub fn with_page_size(self, size: usize) -> Self {
+        self.state.borrow_mut().page_size = size;
+        self
+    }
+
+    /// Sets the versions reported for `version.target`.
+    pub fn with_version(self, version: FwVersion) -> Self {
+        self.state
+            .borrow_mut()
+            .versions
+            .insert(version.target, version);
+        self
+    }
+
+    /// Controls whether `IapReadMemory` is answered, to mimic bootloaders
+    /// that only support the checksum query.
+    pub fn with_read_memory(self, supported: bool) -> Self {
+        self.state.borrow_mut().read_memory = supported;
+        self
+    }
+
+    /// Controls whether `IapGetChecksum` is answered.
+    pub fn with_checksum(self, supported: bool) -> Self {
+        self.state.borrow_mut().checksum = supported;
+        self
+    }
+
+    /// Returns a copy of the whole flash array of `target`.
+    pub fn flash(&self, target: AP2Target) -> Vec<u8> {
+        self.state
+            .borrow()
+            .flash
+            .get(&target)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The last AP flag written, if any.
+    pub fn ap_flag(&self) -> Option<u8> {
+        self.state.borrow().ap_flag
+    }
+
+    /// Whether the bootloader has been told to jump to the application.
+    pub fn booted(&self) -> bool {
+        self.state.borrow().booted
+    }
+
+    fn handle(&self, target: AP2Target, payload: &[u8]) -> Option<Vec<u8>> {
+        let mut state = self.state.borrow_mut();
+        if payload.len() < 2 || payload[0] != L2Command::FW as u8 {
+            return Some(reply(target, payload, STATUS_UNSUPPORTED, &[]));
+        }
+
+        let mut data = Vec::new();
+        let status = match payload[1] {
+            cmd if cmd == KeyCommand::IapEraseMemory as u8 => {
+                let page_size = state.page_size;
+                match (address(payload), state.flash.get_mut(&target)) {
+                    (Some(addr), Some(flash)) if addr < flash.len() && addr % page_size == 0 => {
+                        let end = (addr + page_size).min(flash.len());
+                        flash[addr..end].iter_mut().for_each(|b| *b = 0xff);
+                        STATUS_OK
+                    }
+                    (None, _) => STATUS_BAD_LENGTH,
+                    _ => STATUS_BAD_ADDRESS,
+                }
+            }
+            cmd if cmd == KeyCommand::IapWirteMemory as u8 => {
+                let data = payload.get(6..).unwrap_or_default();
+                match (address(payload), state.flash.get_mut(&target)) {
+                    (Some(addr), Some(flash)) if addr + data.len() <= flash.len() => {
+                        // Like real flash, programming can only clear bits.
+                        flash[addr..addr + data.len()]
+                            .iter_mut()
+                            .zip(data)
+                            .for_each(|(cell, byte)| *cell &= byte);
+                        STATUS_OK
+                    }
+                    (None, _) => STATUS_BAD_LENGTH,
+                    _ => STATUS_BAD_ADDRESS,
+                }
+            }
+            cmd if cmd == KeyCommand::IapReadMemory as u8 && state.read_memory => {
+                let len = payload.get(6).map(|&len| len as usize);
+                match (address(payload), len, state.flash.get(&target)) {
+                    (Some(addr), Some(len), Some(flash)) if addr + len <= flash.len() => {
+                        data.extend_from_slice(&flash[addr..addr + len]);
+                        STATUS_OK
+                    }
+                    (Some(_), Some(_), _) => STATUS_BAD_ADDRESS,
+                    _ => STATUS_BAD_LENGTH,
+                }
+            }
+            cmd if cmd == KeyCommand::IapGetChecksum as u8 && state.checksum => {
+                let len = payload
+                    .get(6..10)
+                    .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize);
+                match (address(payload), len, state.flash.get(&target)) {
+                    (Some(addr), Some(len), Some(flash)) if addr + len <= flash.len() => {
+                        data.extend_from_slice(&crc32(&flash[addr..addr + len]).to_le_bytes());
+                        STATUS_OK
+                    }
+                    (Some(_), Some(_), _) => STATUS_BAD_ADDRESS,
+                    _ => STATUS_BAD_LENGTH,
+                }
+            }
+            cmd if cmd == KeyCommand::IapGetFwVersion as u8 => match state.versions.get(&target) {
+                Some(version) => {
+                    data.extend_from_slice(&version.to_bytes());
+                    STATUS_OK
+                }
+                None => STATUS_UNSUPPORTED,
+            },
+            cmd if cmd == KeyCommand::IapWriteApFlag as u8 => match payload.get(2) {
+                Some(&flag) => {
+                    state.ap_flag = Some(flag);
+                    STATUS_OK
+                }
+                None => STATUS_BAD_LENGTH,
+            },
+            cmd if cmd == KeyCommand::IapMode as u8 => {
+                // Mode switches are never answered; asking the bootloader to
+                // enter IAP mode is a no-op.
+                if payload.get(2) == Some(&MODE_APP) {
+                    state.booted = true;
+                }
+                return None;
+            }
+            cmd if cmd == KeyCommand::IapGetMode as u8 => {
+                data.push(MODE_IAP);
+                STATUS_OK
+            }
+            _ => STATUS_UNSUPPORTED,
+        };
+
+        Some(reply(target, payload, status, &data))
+    }
+}
+
+impl Transport for Emulator {
+    fn write_report(&self, report: &[u8]) -> HidResult<usize> {
+        if self.booted() {
+            return Err(emulator_error("device has left IAP mode"));
+        }
+
+        // Skip the report id, then expect 7b 10 <dst|src> 10 <len> 00 00 7d.
+        let frame = report.get(1..).unwrap_or_default();
+        if frame.len() < HEADER_SIZE || frame[0] != 0x7b || frame[7] != 0x7d {
+            return Err(emulator_error("malformed frame"));
+        }
+        let len = frame[4] as usize;
+        let payload = frame
+            .get(HEADER_SIZE..HEADER_SIZE + len)
+            .ok_or_else(|| emulator_error("payload length exceeds report"))?;
+        let target = match frame[2] >> 4 {
+            3 => AP2Target::McuMain,
+            4 => AP2Target::McuLed,
+            5 => AP2Target::McuBle,
+            _ => return Err(emulator_error("frame not addressed to an MCU")),
+        };
+
+        if let Some(response) = self.handle(target, payload) {
+            self.state.borrow_mut().replies.push_back(response);
+        }
+        Ok(report.len())
+    }
+
+    fn read_report(&self, buf: &mut [u8], _timeout_ms: i32) -> HidResult<usize> {
+        match self.state.borrow_mut().replies.pop_front() {
+            Some(response) => {
+                let len = response.len().min(buf.len());
+                buf[..len].copy_from_slice(&response[..len]);
+                Ok(len)
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+/// Reads the little-endian address that follows the command bytes.
+fn address(payload: &[u8]) -> Option<usize> {
+    payload
+        .get(2..6)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+}
+
+/// Builds a 64-byte reply from `target` to the USB host, echoing the command.
+fn reply(target: AP2Target, request: &[u8], status: u8, data: &[u8]) -> Vec<u8> {
+    let mut payload = request.get(..2).unwrap_or_default().to_vec();
+    payload.push(status);
+    payload.extend_from_slice(data);
+
+    let mut buffer: Vec<u8> = Vec::with_capacity(REPORT_SIZE);
+    buffer.push(0x7b);
+    buffer.push(0x10);
+    buffer.push(((AP2Target::UsbHost as u8) << 4) | target as u8);
+    buffer.push(0x10);
+    buffer.push(payload.len() as u8);
+    buffer.push(0);
+    buffer.push(0);
+    buffer.push(0x7d);
+    buffer.extend_from_slice(&payload);
+    buffer.resize(REPORT_SIZE, 0);
+    buffer
+}
+
+fn emulator_error(message: &str) -> HidError {
+    HidError::HidApiError {
+        message: format!("emulator: {}", message),
+    }
+}
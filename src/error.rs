@@ -0,0 +1,303 @@
+//! The error type shared by every operation in this crate.
+
+use crate::annepro2::{AP2Target, KeyCommand};
+use crate::device::{Mode, Revision};
+use hidapi::HidError;
+use std::{fmt, io};
+
+/// Result of every fallible operation in this crate.
+pub type Result<T> = std::result::Result<T, AP2FlashError>;
+
+/// Everything that can go wrong while finding, talking to or flashing a
+/// keyboard.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum AP2FlashError {
+    /// No Anne Pro 2 is connected, or none in `mode` if that is set.
+    NoDeviceFound { mode: Option<Mode> },
+    /// Several keyboards match and none was picked with a [`Selector`].
+    ///
+    /// [`Selector`]: crate::device::Selector
+    MultipleDeviceFound { mode: Option<Mode> },
+    /// The keyboard at `path` has no serial number, so it cannot be told
+    /// apart from the other connected keyboards once it has rebooted.
+    NoSerialNumber { path: String },
+    /// The operation was stopped through its `CancelToken`.
+    Cancelled,
+    /// A HID operation outside of a command, e.g. opening the device.
+    Usb(HidError),
+    /// Reading the firmware image failed.
+    Io(io::Error),
+    /// The firmware file could not be parsed.
+    InvalidImage(String),
+    /// The batch manifest could not be parsed.
+    InvalidManifest(String),
+    /// A recorded session could not be parsed.
+    InvalidRecording(String),
+    /// The firmware bundle is malformed or failed its checksums.
+    InvalidBundle(String),
+    /// A flash plan is malformed or would not flash safely.
+    InvalidPlan(String),
+    /// The images were built for a different hardware revision.
+    UnsupportedRevision {
+        found: Revision,
+        supported: Vec<Revision>,
+    },
+    /// The image would overwrite the bootloader.
+    BootloaderOverlap { address: u32 },
+    /// Part of the image does not fit in the MCU's application region.
+    OutsideApplicationRegion {
+        target: AP2Target,
+        start: u32,
+        end: u32,
+    },
+    /// The command does not fit in a single report.
+    PayloadTooLarge {
+        command: KeyCommand,
+        target: AP2Target,
+        len: usize,
+    },
+    /// Sending a command or reading its reply failed.
+    Transport {
+        command: KeyCommand,
+        target: AP2Target,
+        address: Option<u32>,
+        source: HidError,
+    },
+    /// The device did not answer before the read timed out.
+    NoResponse {
+        command: KeyCommand,
+        target: AP2Target,
+        address: Option<u32>,
+    },
+    /// The reply could not be decoded or belongs to a different command.
+    UnexpectedResponse {
+        command: KeyCommand,
+        target: AP2Target,
+        address: Option<u32>,
+        report: Vec<u8>,
+    },
+    /// The device answered with a non-success status.
+    Rejected {
+        command: KeyCommand,
+        target: AP2Target,
+        address: Option<u32>,
+        status: u8,
+    },
+    /// A chunk could not be written even after retrying.
+    WriteFailed {
+        target: AP2Target,
+        address: u32,
+        attempts: u32,
+        source: Box<AP2FlashError>,
+    },
+    /// Flash read back differs from the image that was written.
+    VerifyMismatch { target: AP2Target, address: u32 },
+    /// The bootloader answers neither the read-back nor the checksum query,
+    /// so what was written cannot be checked.
+    VerifyUnsupported {
+        target: AP2Target,
+        source: Box<AP2FlashError>,
+    },
+}
+
+impl AP2FlashError {
+    /// The device status byte, if the device rejected a command.
+    pub fn status(&self) -> Option<u8> {
+        match self {
+            AP2FlashError::Rejected { status, .. } => Some(*status),
+            AP2FlashError::WriteFailed { source, .. } => source.status(),
+            _ => None,
+        }
+    }
+
+    /// The flash address the failure relates to, if any.
+    pub fn address(&self) -> Option<u32> {
+        match self {
+            AP2FlashError::Transport { address, .. }
+            | AP2FlashError::NoResponse { address, .. }
+            | AP2FlashError::UnexpectedResponse { address, .. }
+            | AP2FlashError::Rejected { address, .. } => *address,
+            AP2FlashError::BootloaderOverlap { address }
+            | AP2FlashError::OutsideApplicationRegion { start: address, .. }
+            | AP2FlashError::WriteFailed { address, .. }
+            | AP2FlashError::VerifyMismatch { address, .. } => Some(*address),
+            _ => None,
+        }
+    }
+}
+
+/// Formats " in IAP mode" for errors about finding a keyboard in a mode.
+struct In(Option<Mode>);
+
+impl fmt::Display for In {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(Mode::Iap) => write!(f, " in IAP mode"),
+            Some(Mode::Normal) => write!(f, " in normal mode"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Formats " at 0x004000" for errors that carry an address.
+struct At(Option<u32>);
+
+impl fmt::Display for At {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(addr) => write!(f, " at {:#08x}", addr),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for AP2FlashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AP2FlashError::NoDeviceFound { mode } => {
+                write!(f, "no Anne Pro 2{} found", In(*mode))
+            }
+            AP2FlashError::MultipleDeviceFound { mode } => {
+                write!(f, "more than one Anne Pro 2{} found", In(*mode))
+            }
+            AP2FlashError::NoSerialNumber { path } => write!(
+                f,
+                "keyboard at {} has no serial number to find it by after rebooting \
+                 while other keyboards are connected",
+                path
+            ),
+            AP2FlashError::Cancelled => write!(f, "cancelled"),
+            AP2FlashError::Usb(err) => write!(f, "USB error: {}", err),
+            AP2FlashError::Io(err) => write!(f, "I/O error: {}", err),
+            AP2FlashError::InvalidImage(reason) => write!(f, "invalid firmware image: {}", reason),
+            AP2FlashError::InvalidManifest(reason) => write!(f, "invalid manifest: {}", reason),
+            AP2FlashError::InvalidRecording(reason) => write!(f, "invalid recording: {}", reason),
+            AP2FlashError::InvalidBundle(reason) => write!(f, "invalid bundle: {}", reason),
+            AP2FlashError::InvalidPlan(reason) => write!(f, "invalid flash plan: {}", reason),
+            AP2FlashError::UnsupportedRevision { found, supported } => {
+                let supported: Vec<String> = supported.iter().map(Revision::to_string).collect();
+                write!(
+                    f,
+                    "keyboard is a {} but the images are for {}",
+                    found,
+                    supported.join(", ")
+                )
+            }
+            AP2FlashError::BootloaderOverlap { address } => write!(
+                f,
+                "image starts at {:#08x}, inside the bootloader region",
+                address
+            ),
+            AP2FlashError::OutsideApplicationRegion { target, start, end } => write!(
+                f,
+                "data at {:#08x}..{:#08x} is outside the application region of {:?}",
+                start, end, target
+            ),
+            AP2FlashError::PayloadTooLarge {
+                command,
+                target,
+                len,
+            } => write!(
+                f,
+                "{:?} payload for {:?} is {} bytes, too large for one report",
+                command, target, len
+            ),
+            AP2FlashError::Transport {
+                command,
+                target,
+                address,
+                source,
+            } => write!(
+                f,
+                "USB error sending {:?} to {:?}{}: {}",
+                command,
+                target,
+                At(*address),
+                source
+            ),
+            AP2FlashError::NoResponse {
+                command,
+                target,
+                address,
+            } => write!(
+                f,
+                "no response to {:?} from {:?}{}",
+                command,
+                target,
+                At(*address)
+            ),
+            AP2FlashError::UnexpectedResponse {
+                command,
+                target,
+                address,
+                ..
+            } => write!(
+                f,
+                "unexpected response to {:?} from {:?}{}",
+                command,
+                target,
+                At(*address)
+            ),
+            AP2FlashError::Rejected {
+                command,
+                target,
+                address,
+                status,
+            } => write!(
+                f,
+                "{:?} rejected by {:?}{} (status {:#04x})",
+                command,
+                target,
+                At(*address),
+                status
+            ),
+            AP2FlashError::WriteFailed {
+                target,
+                address,
+                attempts,
+                source,
+            } => write!(
+                f,
+                "writing {:?} at {:#08x} failed after {} attempts: {}",
+                target, address, attempts, source
+            ),
+            AP2FlashError::VerifyMismatch { target, address } => write!(
+                f,
+                "verification of {:?} failed: mismatch at {:#08x}",
+                target, address
+            ),
+            AP2FlashError::VerifyUnsupported { target, source } => write!(
+                f,
+                "{:?} cannot be verified, its bootloader supports neither reading \
+                 memory nor checksums: {}",
+                target, source
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AP2FlashError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AP2FlashError::Usb(err) => Some(err),
+            AP2FlashError::Transport { source, .. } => Some(source),
+            AP2FlashError::Io(err) => Some(err),
+            AP2FlashError::WriteFailed { source, .. }
+            | AP2FlashError::VerifyUnsupported { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<HidError> for AP2FlashError {
+    fn from(err: HidError) -> Self {
+        AP2FlashError::Usb(err)
+    }
+}
+
+impl From<io::Error> for AP2FlashError {
+    fn from(err: io::Error) -> Self {
+        AP2FlashError::Io(err)
+    }
+}
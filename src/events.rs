@@ -0,0 +1,80 @@
+//! Typed progress events, so frontends can follow a flash session without
+//! scraping its output.
+
+use crate::annepro2::AP2Target;
+use crate::device::AP2Device;
+use std::ops::Range;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+/// Something that happened during a flash session, in the order listed.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum FlashEvent {
+    /// The keyboard to flash was found.
+    DeviceFound(AP2Device),
+    EraseStarted {
+        target: AP2Target,
+        range: Range<u32>,
+    },
+    EraseFinished {
+        target: AP2Target,
+        range: Range<u32>,
+    },
+    /// Writing the image of `target` begins; `total` bytes will be written.
+    WriteStarted {
+        target: AP2Target,
+        total: usize,
+    },
+    /// `len` bytes were written at `address`, `written` of `total` so far.
+    ChunkWritten {
+        target: AP2Target,
+        address: u32,
+        len: usize,
+        written: usize,
+        total: usize,
+    },
+    /// Writing the chunk at `address` failed and is retried after `delay`.
+    Retry {
+        target: AP2Target,
+        address: u32,
+        attempt: u32,
+        delay: Duration,
+        error: String,
+    },
+    /// `len` bytes at `address` were read back and match the image.
+    Verified {
+        target: AP2Target,
+        address: u32,
+        len: usize,
+    },
+    ApFlagWritten {
+        flag: u8,
+    },
+    /// The keyboard was told to start its firmware.
+    Booted,
+}
+
+/// Receives [`FlashEvent`]s as they happen.
+pub trait Observer {
+    fn on_event(&self, event: &FlashEvent);
+}
+
+/// Ignores every event.
+impl Observer for () {
+    fn on_event(&self, _event: &FlashEvent) {}
+}
+
+impl<F: Fn(&FlashEvent)> Observer for F {
+    fn on_event(&self, event: &FlashEvent) {
+        self(event)
+    }
+}
+
+/// Forwards events to another thread, e.g. a GUI's event loop. Events sent
+/// after the receiver is gone are dropped.
+impl Observer for Sender<FlashEvent> {
+    fn on_event(&self, event: &FlashEvent) {
+        let _ = self.send(event.clone());
+    }
+}
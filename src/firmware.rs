@@ -0,0 +1,254 @@
+//! Loading firmware images from disk into address/data segments.
+
+use crate::annepro2::AP2Target;
+use crate::device::Revision;
+use crate::error::{AP2FlashError, Result};
+use crate::memory_map::MemoryMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const PT_LOAD: u32 = 1;
+
+/// A contiguous run of bytes to be written starting at `address`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    /// The first address after this segment, or `None` if the segment runs
+    /// past the end of the 32-bit address space.
+    pub fn end(&self) -> Option<u32> {
+        u32::try_from(self.data.len())
+            .ok()
+            .and_then(|len| self.address.checked_add(len))
+    }
+}
+
+/// Loads `path` as ELF or Intel HEX if it looks like one, otherwise as a raw
+/// binary placed at `base`. The result is sorted; use `check_fits` to make
+/// sure it suits the target.
+pub fn load(path: &Path, base: u32) -> Result<Vec<Segment>> {
+    let bytes = fs::read(path)?;
+    let hex_extension = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("hex") || ext.eq_ignore_ascii_case("ihex"));
+    let segments = if bytes.starts_with(ELF_MAGIC) {
+        parse_elf(&bytes)?
+    } else if hex_extension || (bytes.first() == Some(&b':') && bytes.is_ascii()) {
+        let text = String::from_utf8(bytes)
+            .map_err(|_| AP2FlashError::InvalidImage("HEX file is not valid text".into()))?;
+        parse_ihex(&text)?
+    } else {
+        normalize(vec![Segment {
+            address: base,
+            data: bytes,
+        }])?
+    };
+    Ok(segments)
+}
+
+/// Parses Intel HEX records into segments, merging contiguous data records
+/// and keeping gaps as separate segments.
+pub fn parse_ihex(text: &str) -> Result<Vec<Segment>> {
+    let mut upper = 0u32;
+    let mut segments: Vec<Segment> = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = |reason: &str| {
+            AP2FlashError::InvalidImage(format!("HEX line {}: {}", index + 1, reason))
+        };
+
+        let digits = line
+            .strip_prefix(':')
+            .ok_or_else(|| invalid("missing ':' start code"))?;
+        if !digits.is_ascii() || digits.len() % 2 != 0 {
+            return Err(invalid("malformed record"));
+        }
+        let record = (0..digits.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .map_err(|_| invalid("invalid hex digit"))?;
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            return Err(invalid("record length mismatch"));
+        }
+        if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(invalid("bad checksum"));
+        }
+
+        let offset = u16::from_be_bytes([record[1], record[2]]) as u32;
+        let data = &record[4..record.len() - 1];
+        match (record[3], data.len()) {
+            (0x00, _) => {
+                let address = upper
+                    .checked_add(offset)
+                    .filter(|addr| addr.checked_add(data.len() as u32).is_some())
+                    .ok_or_else(|| invalid("address out of range"))?;
+                match segments.last_mut() {
+                    Some(segment) if segment.end() == Some(address) => {
+                        segment.data.extend_from_slice(data)
+                    }
+                    _ => segments.push(Segment {
+                        address,
+                        data: data.to_vec(),
+                    }),
+                }
+            }
+            (0x01, _) => break,
+            (0x02, 2) => upper = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4,
+            (0x04, 2) => upper = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+            // Start addresses only matter to debuggers.
+            (0x03, 4) | (0x05, 4) => {}
+            (0x02, _) | (0x03, _) | (0x04, _) | (0x05, _) => {
+                return Err(invalid("record length mismatch"))
+            }
+            (kind, _) => return Err(invalid(&format!("unknown record type {:#04x}", kind))),
+        }
+    }
+    normalize(segments)
+}
+
+/// Extracts the loadable segments of a 32-bit little-endian ELF file, placed
+/// at their physical (load) addresses.
+pub fn parse_elf(bytes: &[u8]) -> Result<Vec<Segment>> {
+    let invalid = |reason: &str| AP2FlashError::InvalidImage(format!("ELF: {}", reason));
+    let u16_at = |offset: usize| {
+        bytes
+            .get(offset..offset.checked_add(2)?)
+            .map(|it| u16::from_le_bytes([it[0], it[1]]))
+    };
+    let u32_at = |offset: usize| {
+        bytes
+            .get(offset..offset.checked_add(4)?)
+            .map(|it| u32::from_le_bytes([it[0], it[1], it[2], it[3]]))
+    };
+
+    if !bytes.starts_with(ELF_MAGIC) {
+        return Err(invalid("bad magic"));
+    }
+    if bytes.get(4) != Some(&1) || bytes.get(5) != Some(&1) {
+        return Err(invalid("only 32-bit little-endian files are supported"));
+    }
+    let (phoff, phentsize, phnum) = match (u32_at(0x1c), u16_at(0x2a), u16_at(0x2c)) {
+        (Some(phoff), Some(phentsize), Some(phnum)) => {
+            (phoff as usize, phentsize as usize, phnum as usize)
+        }
+        _ => return Err(invalid("truncated header")),
+    };
+
+    let mut segments = Vec::new();
+    for index in 0..phnum {
+        let header = index
+            .checked_mul(phentsize)
+            .and_then(|it| it.checked_add(phoff))
+            .ok_or_else(|| invalid("truncated program header"))?;
+        let field = |offset: usize| {
+            header
+                .checked_add(offset)
+                .and_then(u32_at)
+                .ok_or_else(|| invalid("truncated program header"))
+        };
+        if field(0)? != PT_LOAD {
+            continue;
+        }
+        let offset = field(4)? as usize;
+        let paddr = field(12)?;
+        let filesz = field(16)? as usize;
+        let data = offset
+            .checked_add(filesz)
+            .and_then(|end| bytes.get(offset..end))
+            .ok_or_else(|| invalid("segment data past end of file"))?;
+        if paddr.checked_add(filesz as u32).is_none() {
+            return Err(invalid("segment address out of range"));
+        }
+        segments.push(Segment {
+            address: paddr,
+            data: data.to_vec(),
+        });
+    }
+    normalize(segments)
+}
+
+/// Ensures every segment lies inside the application region of `target`.
+/// With no `revision` the segments only need to fit on one of them; the
+/// exact check happens once the keyboard is known.
+pub fn check_fits(
+    segments: &[Segment],
+    target: AP2Target,
+    revision: Option<Revision>,
+) -> Result<()> {
+    let maps = match revision {
+        Some(revision) => MemoryMap::of(target, revision).into_iter().collect(),
+        None => MemoryMap::all(target),
+    };
+    if maps.is_empty() {
+        return Err(AP2FlashError::InvalidImage(format!(
+            "{:?} cannot be flashed",
+            target
+        )));
+    }
+    for segment in segments {
+        if segment.end().is_none() {
+            return Err(past_address_space(segment));
+        }
+        if maps
+            .iter()
+            .all(|map| segment.address < map.application_start)
+        {
+            return Err(AP2FlashError::BootloaderOverlap {
+                address: segment.address,
+            });
+        }
+    }
+    match segments.iter().find(|segment| {
+        !maps.iter().any(|map| {
+            let region = map.application_region();
+            segment.address >= region.start && segment.end().is_some_and(|end| end <= region.end)
+        })
+    }) {
+        Some(segment) => Err(AP2FlashError::OutsideApplicationRegion {
+            target,
+            start: segment.address,
+            end: segment.end().unwrap_or(u32::MAX),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Sorts segments, merges the ones that touch and rejects overlaps.
+pub fn normalize(mut segments: Vec<Segment>) -> Result<Vec<Segment>> {
+    segments.retain(|it| !it.data.is_empty());
+    segments.sort_by_key(|it| it.address);
+    let mut merged: Vec<Segment> = Vec::with_capacity(segments.len());
+    for segment in segments {
+        if segment.end().is_none() {
+            return Err(past_address_space(&segment));
+        }
+        match merged.last_mut() {
+            Some(last) if last.end() > Some(segment.address) => {
+                return Err(AP2FlashError::InvalidImage(format!(
+                    "data at {:#08x} overlaps data at {:#08x}",
+                    segment.address, last.address
+                )));
+            }
+            Some(last) if last.end() == Some(segment.address) => last.data.extend(segment.data),
+            _ => merged.push(segment),
+        }
+    }
+    Ok(merged)
+}
+
+fn past_address_space(segment: &Segment) -> AP2FlashError {
+    AP2FlashError::InvalidImage(format!(
+        "{} bytes at {:#08x} run past the end of the address space",
+        segment.data.len(),
+        segment.address
+    ))
+}
@@ -0,0 +1,62 @@
+//! Flashing firmware onto Anne Pro 2 keyboards over their IAP bootloader.
+//!
+//! The `annepro2_tools` binary is a thin command line front end to this
+//! crate; everything it does is available here for other tools to embed.
+//!
+//! * [`device`] finds connected keyboards and tells revisions and modes apart.
+//! * [`firmware`], [`manifest`] and [`bundle`] load images from disk.
+//! * [`annepro2`] speaks the IAP protocol: erase, write, verify, set the AP
+//!   flag and boot, either one command at a time or as a whole session with
+//!   [`flash_batch`].
+//! * [`plan`] works out every command of a session up front, so it can be
+//!   reviewed, saved and run later with [`flash_plan`].
+//! * [`AnnePro2`] is an opened keyboard, for running individual commands.
+//! * [`events`] reports progress to GUIs and other frontends.
+//! * [`transport`] abstracts the HID handle, so sessions can also be run
+//!   against the [`testing::Emulator`] or a [`record`]ed session.
+//!
+//! ```no_run
+//! use annepro2_tools::{flash_batch, AP2Target, CancelToken, FlashJob, FlashOptions, Selector};
+//! use std::path::Path;
+//!
+//! let segments = annepro2_tools::firmware::load(Path::new("annepro2_c15.bin"), 0x4000)?;
+//! let job = FlashJob { target: AP2Target::McuMain, segments };
+//! let report = flash_batch(&[job], &Selector::default(), &FlashOptions::default(), &(), &CancelToken::new());
+//! if let Some(err) = report.error {
+//!     eprintln!("flashing failed: {}", err);
+//! }
+//! # Ok::<(), annepro2_tools::AP2FlashError>(())
+//! ```
+
+pub mod annepro2;
+pub mod bundle;
+pub mod cancel;
+mod checksum;
+pub mod device;
+pub mod dry_run;
+mod emulator;
+pub mod error;
+pub mod events;
+pub mod firmware;
+pub mod manifest;
+pub mod memory_map;
+pub mod plan;
+pub mod record;
+mod response;
+pub mod session;
+pub mod testing;
+pub mod transport;
+pub mod version;
+
+pub use crate::annepro2::{
+    flash_batch, flash_plan, flash_session, AP2Target, BatchReport, FlashJob, FlashOptions,
+    RetryPolicy, TargetReport,
+};
+pub use crate::cancel::CancelToken;
+pub use crate::device::{AP2Device, Mode, Revision, Selector};
+pub use crate::error::{AP2FlashError, Result};
+pub use crate::events::{FlashEvent, Observer};
+pub use crate::firmware::Segment;
+pub use crate::plan::FlashPlan;
+pub use crate::session::AnnePro2;
+pub use crate::transport::Transport;
@@ -1,10 +1,38 @@
-use crate::annepro2::AP2Target;
-use std::fs::File;
+use annepro2_tools::bundle::{Bundle, Image};
+use annepro2_tools::plan::{self, Step};
+use annepro2_tools::record::Replay;
+use annepro2_tools::version::FwVersion;
+use annepro2_tools::{annepro2, bundle, device, dry_run, firmware, manifest, memory_map};
+use annepro2_tools::{
+    AP2Device, AP2FlashError, AP2Target, AnnePro2, BatchReport, CancelToken, FlashEvent, FlashJob,
+    FlashOptions, FlashPlan, Mode, Observer, RetryPolicy, Revision, Selector,
+};
+use hidapi::HidApi;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, warn, LevelFilter};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::ffi::OsString;
 use std::num::ParseIntError;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
 use structopt::StructOpt;
 
-pub mod annepro2;
+/// Distinct exit codes so wrapper scripts can tell "nothing to flash" apart
+/// from a failed flash without parsing the message.
+fn exit_code(err: &AP2FlashError) -> i32 {
+    match err {
+        AP2FlashError::NoDeviceFound { .. }
+        | AP2FlashError::MultipleDeviceFound { .. }
+        | AP2FlashError::NoSerialNumber { .. } => 2,
+        AP2FlashError::Rejected { .. }
+        | AP2FlashError::WriteFailed { .. }
+        | AP2FlashError::VerifyMismatch { .. } => 3,
+        AP2FlashError::Cancelled => 130,
+        _ => 1,
+    }
+}
 
 fn parse_hex(src: &str) -> std::result::Result<u32, ParseIntError> {
     if let Some(num) = src.strip_prefix("0x") {
@@ -14,41 +42,839 @@ fn parse_hex(src: &str) -> std::result::Result<u32, ParseIntError> {
     }
 }
 
+/// First arguments that name a subcommand or ask for help or the version.
+const COMMANDS: &[&str] = &[
+    "flash",
+    "batch",
+    "bundle",
+    "execute",
+    "list",
+    "mode",
+    "version",
+    "help",
+    "-h",
+    "--help",
+    "-V",
+    "--version",
+];
+
+/// Inserts `flash` in front of the arguments unless they start with a
+/// subcommand, so `annepro2_tools [--boot] a.bin` keeps working as it did
+/// before there were subcommands. Leading `-q`/`-v` flags are skipped, as
+/// they are accepted anywhere.
+fn with_default_command(args: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+    let mut args: Vec<OsString> = args.into_iter().collect();
+    let first = args
+        .iter()
+        .skip(1)
+        .position(|arg| !arg.to_str().is_some_and(is_global_flag))
+        .map(|it| it + 1);
+    if let Some(first) = first {
+        if !args[first]
+            .to_str()
+            .is_some_and(|it| COMMANDS.contains(&it))
+        {
+            args.insert(first, "flash".into());
+        }
+    }
+    args
+}
+
+fn is_global_flag(arg: &str) -> bool {
+    match arg.strip_prefix('-') {
+        Some("-quiet") | Some("-verbose") => true,
+        Some(flags) => !flags.is_empty() && flags.chars().all(|c| c == 'q' || c == 'v'),
+        None => false,
+    }
+}
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "annepro2_tools")]
-struct ArgOpts {
-    #[structopt(long, parse(try_from_str = parse_hex), default_value = "0x4000")]
-    base: u32,
+struct Opt {
+    /// Only print warnings and errors
+    #[structopt(short, long, global = true)]
+    quiet: bool,
+    /// Print more details, twice to also dump every report
+    #[structopt(short, long, parse(from_occurrences), global = true)]
+    verbose: u8,
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Flash firmware onto a keyboard in IAP mode
+    Flash(FlashOpts),
+    /// Flash every image listed in a TOML manifest in one session
+    Batch {
+        #[structopt(flatten)]
+        session: SessionOpts,
+        /// Manifest listing the target, file and optional base of each image
+        #[structopt(name = "manifest", parse(from_os_str))]
+        manifest: PathBuf,
+    },
+    /// Create, inspect or flash a firmware bundle
+    Bundle(BundleCommand),
+    /// Run a plan saved with --save-plan; the plan decides what is erased,
+    /// verified and booted
+    Execute {
+        #[structopt(flatten)]
+        run: RunOpts,
+        /// Plan to run, as written by --save-plan
+        #[structopt(name = "file", parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// List connected Anne Pro 2 keyboards
+    List {
+        /// Print JSON instead of a table
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Show which mode a keyboard is in, or reboot it into IAP mode
+    Mode {
+        /// Reboot the keyboard into IAP mode and wait for it to come back
+        #[structopt(long)]
+        iap: bool,
+        /// Seconds to wait for the keyboard to come back in IAP mode
+        #[structopt(long, default_value = "10")]
+        timeout: u64,
+        #[structopt(flatten)]
+        selector: SelectorOpts,
+    },
+    /// Show bootloader and application versions of every MCU
+    Version {
+        #[structopt(flatten)]
+        selector: SelectorOpts,
+        /// Print JSON instead of a table
+        #[structopt(long)]
+        json: bool,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+enum BundleCommand {
+    /// Pack the images listed in a TOML manifest into a bundle
+    Create {
+        /// Manifest listing the target, file, base and version of each image
+        #[structopt(name = "manifest", parse(from_os_str))]
+        manifest: PathBuf,
+        /// Where to write the bundle
+        #[structopt(short = "o", long, parse(from_os_str))]
+        output: PathBuf,
+    },
+    /// Check a bundle's checksums and show what it contains
+    Inspect {
+        #[structopt(name = "bundle", parse(from_os_str))]
+        bundle: PathBuf,
+        /// Print the bundle header as JSON
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Flash every image in a bundle in one session
+    Flash {
+        #[structopt(flatten)]
+        session: SessionOpts,
+        #[structopt(name = "bundle", parse(from_os_str))]
+        bundle: PathBuf,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+struct SelectorOpts {
+    /// Only use the keyboard with this USB serial number
+    #[structopt(long)]
+    serial: Option<String>,
+    /// Only use the keyboard at this HID path, as shown by `list`
+    #[structopt(long)]
+    path: Option<String>,
+    /// Only use the keyboard with this index, as shown by `list`
+    #[structopt(long)]
+    index: Option<usize>,
+}
+
+impl SelectorOpts {
+    fn selector(&self) -> Selector {
+        Selector {
+            serial: self.serial.clone(),
+            path: self.path.clone(),
+            index: self.index,
+        }
+    }
+}
+
+/// What goes into the plan of a session; `execute` takes all of it from the
+/// saved plan instead.
+#[derive(StructOpt, Debug)]
+struct SessionOpts {
     #[structopt(long = "boot")]
     boot: bool,
+    /// Experimental: read the image back after flashing and compare it, using
+    /// bootloader commands that are guessed and unconfirmed on real hardware
+    #[structopt(long = "experimental-verify")]
+    verify: bool,
+    /// Erase the whole application region, not just the pages being written
+    #[structopt(long)]
+    full_erase: bool,
+    /// Keyboard revision to assume for --dry-run, --replay and plans, c15 or c18
+    #[structopt(long)]
+    revision: Option<Revision>,
+    #[structopt(flatten)]
+    run: RunOpts,
+}
+
+/// How a planned session is run.
+#[derive(StructOpt, Debug)]
+struct RunOpts {
+    /// Print every report that would be sent instead of opening a keyboard
+    #[structopt(long)]
+    dry_run: bool,
+    /// Log every report sent to and read from the keyboard to this file
+    #[structopt(long, parse(from_os_str))]
+    record: Option<PathBuf>,
+    /// Play a file written by --record back instead of opening a keyboard
+    #[structopt(long, parse(from_os_str), conflicts_with = "dry-run")]
+    replay: Option<PathBuf>,
+    /// Print every step of the session and how long it should take instead of flashing
+    #[structopt(long)]
+    plan: bool,
+    /// Write every step of the session to this file as JSON instead of flashing
+    #[structopt(long, parse(from_os_str))]
+    save_plan: Option<PathBuf>,
+    /// Times a failed chunk write is retried before aborting
+    #[structopt(long, default_value = "3")]
+    retries: u32,
+    /// Milliseconds to wait before the first retry, doubled for each further retry
+    #[structopt(long, default_value = "100")]
+    retry_delay: u64,
+    /// Seconds to wait for a keyboard in IAP mode to be plugged in
+    #[structopt(long, default_value = "10")]
+    wait: u64,
+    #[structopt(flatten)]
+    selector: SelectorOpts,
+}
+
+impl SessionOpts {
+    fn options(&self, revisions: Vec<Revision>) -> FlashOptions {
+        let mut options = self.run.options();
+        options.boot = self.boot;
+        options.verify = self.verify;
+        options.full_erase = self.full_erase;
+        options.revisions = revisions;
+        options
+    }
+}
+
+impl RunOpts {
+    fn options(&self) -> FlashOptions {
+        let mut options = FlashOptions::default();
+        options.record = self.record.clone();
+        options.retry = RetryPolicy {
+            retries: self.retries,
+            backoff: Duration::from_millis(self.retry_delay),
+        };
+        options.wait = Duration::from_secs(self.wait);
+        options
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct FlashOpts {
+    /// Address to flash a raw binary at, the target's application start by
+    /// default; HEX and ELF files carry their own addresses
+    #[structopt(long, parse(try_from_str = parse_hex))]
+    base: Option<u32>,
     #[structopt(short = "t", long, default_value = "main")]
-    target: String,
-    /// File to be flashed onto device
+    target: AP2Target,
+    #[structopt(flatten)]
+    session: SessionOpts,
+    /// File to be flashed onto device, raw binary, Intel HEX or ELF
     #[structopt(name = "file", parse(from_os_str))]
     file: PathBuf,
 }
 
 fn main() {
-    let args: ArgOpts = ArgOpts::from_args();
-    println!("args: {:#x?}", args);
-    let mut file = File::open(args.file).expect("invalid file");
-    let target;
-    if args.target.eq_ignore_ascii_case("ble") {
-        target = AP2Target::McuBle;
-    } else if args.target.eq_ignore_ascii_case("main") {
-        target = AP2Target::McuMain;
-    } else if args.target.eq_ignore_ascii_case("led") {
-        target = AP2Target::McuLed;
+    let opt = Opt::from_iter(with_default_command(std::env::args_os()));
+    let level = match (opt.quiet, opt.verbose) {
+        (true, _) => LevelFilter::Warn,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    env_logger::Builder::new()
+        .filter_level(level)
+        .format_timestamp(None)
+        .format_target(false)
+        .init();
+
+    match opt.command {
+        Command::Flash(args) => flash(args),
+        Command::Batch { session, manifest } => batch(&session, &manifest),
+        Command::Bundle(BundleCommand::Create { manifest, output }) => {
+            bundle_create(&manifest, &output)
+        }
+        Command::Bundle(BundleCommand::Inspect { bundle, json }) => bundle_inspect(&bundle, json),
+        Command::Bundle(BundleCommand::Flash { session, bundle }) => {
+            bundle_flash(&session, &bundle)
+        }
+        Command::Execute { run, file } => execute(&run, &file),
+        Command::List { json } => list(json),
+        Command::Mode {
+            iap,
+            timeout,
+            selector,
+        } => mode(&selector.selector(), iap, timeout),
+        Command::Version { selector, json } => version(&selector.selector(), json),
+    }
+}
+
+fn flash(args: FlashOpts) {
+    debug!("args: {:#x?}", args);
+    let base = args
+        .base
+        .unwrap_or_else(|| memory_map::default_base(args.target));
+    let segments = match firmware::load(&args.file, base) {
+        Ok(segments) => segments,
+        Err(err) => {
+            eprintln!("Unable to load {}: {}", args.file.display(), err);
+            process::exit(1);
+        }
+    };
+    let bundle = Bundle {
+        revisions: Vec::new(),
+        images: vec![Image {
+            target: args.target,
+            version: None,
+            segments,
+        }],
+    };
+    run_session(&bundle, &args.session);
+}
+
+fn batch(session: &SessionOpts, manifest: &Path) {
+    debug!("args: {:#x?}", session);
+    let bundle = match manifest::load(manifest) {
+        Ok(bundle) => bundle,
+        Err(err) => {
+            eprintln!("Unable to load {}: {}", manifest.display(), err);
+            process::exit(1);
+        }
+    };
+    run_session(&bundle, session);
+}
+
+fn bundle_create(manifest: &Path, output: &Path) {
+    let result = manifest::load(manifest).and_then(|bundle| {
+        bundle::write(output, &bundle)?;
+        Ok(bundle)
+    });
+    match result {
+        Ok(bundle) => {
+            println!("Wrote {}", output.display());
+            print_bundle(&bundle);
+        }
+        Err(err) => {
+            eprintln!("Unable to create bundle: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+fn bundle_inspect(path: &Path, json: bool) {
+    let bundle = load_bundle(path);
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&bundle.header()).expect("header is serializable")
+        );
     } else {
-        panic!("Invalid target, choose from main, led, and ble");
+        print_bundle(&bundle);
     }
-    let result = annepro2::flash_firmware(target, args.base, &mut file, args.boot);
-    if result.is_ok() {
-        println!("Flash complete");
-        if args.boot {
-            println!("Booting Keyboard");
+}
+
+fn bundle_flash(session: &SessionOpts, path: &Path) {
+    debug!("args: {:#x?}", session);
+    let bundle = load_bundle(path);
+    print_bundle(&bundle);
+    run_session(&bundle, session);
+}
+
+fn load_bundle(path: &Path) -> Bundle {
+    match bundle::read(path) {
+        Ok(bundle) => bundle,
+        Err(err) => {
+            eprintln!("Unable to load {}: {}", path.display(), err);
+            process::exit(1);
+        }
+    }
+}
+
+fn execute(run: &RunOpts, path: &Path) {
+    debug!("args: {:#x?}", run);
+    let plan = match plan::read(path) {
+        Ok(plan) => plan,
+        Err(err) => {
+            eprintln!("Unable to load {}: {}", path.display(), err);
+            process::exit(1);
+        }
+    };
+    run_plan(&plan, run, &run.options());
+}
+
+fn run_session(bundle: &Bundle, session: &SessionOpts) {
+    let jobs = bundle.jobs();
+    let options = session.options(bundle.revisions.clone());
+    let run = &session.run;
+    let offline = run.dry_run || run.replay.is_some() || run.plan || run.save_plan.is_some();
+    if !offline {
+        // The revision, and with it the plan, is only known once the
+        // keyboard has been found.
+        let bars = ProgressBars::default();
+        let report = annepro2::flash_batch(
+            &jobs,
+            &run.selector.selector(),
+            &options,
+            bars.observer(run),
+            &cancel_on_ctrl_c(),
+        );
+        finish_session(&jobs, &report, run);
+        return;
+    }
+
+    let revision = session
+        .revision
+        .or_else(|| bundle.revisions.first().copied())
+        .unwrap_or(Revision::C15);
+    match FlashPlan::new(&jobs, revision, &options) {
+        Ok(plan) => run_plan(&plan, run, &options),
+        Err(err) => {
+            eprintln!("Unable to plan the session: {}", err);
+            process::exit(exit_code(&err));
         }
+    }
+}
+
+fn run_plan(plan: &FlashPlan, run: &RunOpts, options: &FlashOptions) {
+    if let Some(path) = &run.save_plan {
+        if let Err(err) = plan::write(path, plan) {
+            eprintln!("Unable to write {}: {}", path.display(), err);
+            process::exit(1);
+        }
+        println!("Wrote {}", path.display());
+    }
+    if run.plan {
+        print_plan(plan);
+    }
+    if run.plan || run.save_plan.is_some() {
+        return;
+    }
+
+    let bars = ProgressBars::default();
+    let observer = bars.observer(run);
+    let cancel = cancel_on_ctrl_c();
+    let report = if run.dry_run {
+        println!("Dry run against an emulated {}", plan.revision);
+        dry_run::run(plan, options, observer, &cancel, std::io::stdout())
+    } else if let Some(path) = &run.replay {
+        replay(path, plan, options, observer, &cancel)
+    } else {
+        annepro2::flash_plan(plan, &run.selector.selector(), options, observer, &cancel)
+    };
+    finish_session(&plan.jobs(), &report, run);
+}
+
+fn finish_session(jobs: &[FlashJob], report: &BatchReport, run: &RunOpts) {
+    print_report(jobs, report);
+    match &report.error {
+        None if run.dry_run => println!("Dry run complete, nothing was written"),
+        None if run.replay.is_some() => println!("Replay complete"),
+        None => {
+            println!("Flash complete");
+            if report.booted {
+                println!("Booting Keyboard");
+            }
+        }
+        Some(err) => {
+            match err {
+                AP2FlashError::Cancelled => eprintln!("Flash cancelled"),
+                _ => eprintln!("Flash error: {}", err),
+            }
+            if let AP2FlashError::MultipleDeviceFound { .. } = err {
+                eprintln!("Pick one with --serial, --path or --index, see `list`.");
+            }
+            if report.needs_reflash() {
+                eprintln!("The AP flag was not written, so the keyboard remains in IAP mode.");
+                eprintln!("Flash it again to get a working keyboard.");
+            }
+            process::exit(exit_code(err));
+        }
+    }
+}
+
+fn replay(
+    path: &Path,
+    plan: &FlashPlan,
+    options: &FlashOptions,
+    observer: &dyn Observer,
+    cancel: &CancelToken,
+) -> BatchReport {
+    let replay = match Replay::load(path) {
+        Ok(replay) => replay,
+        Err(err) => {
+            eprintln!("Unable to load {}: {}", path.display(), err);
+            process::exit(1);
+        }
+    };
+    let report = plan.execute(&replay, options, observer, cancel);
+    if report.error.is_none() && !replay.is_finished() {
+        eprintln!("Warning: the recording continues past the end of the session");
+    }
+    report
+}
+
+/// Returns a token that is cancelled by the first Ctrl-C. A second one
+/// exits straight away.
+fn cancel_on_ctrl_c() -> CancelToken {
+    let cancel = CancelToken::new();
+    let handler = cancel.clone();
+    let result = ctrlc::set_handler(move || {
+        if handler.is_cancelled() {
+            process::exit(130);
+        }
+        eprintln!("Stopping after the current command...");
+        handler.cancel();
+    });
+    if let Err(err) = result {
+        warn!("Unable to handle Ctrl-C: {}", err);
+    }
+    cancel
+}
+
+/// Draws a progress bar for each MCU while it is written.
+#[derive(Default)]
+struct ProgressBars {
+    current: RefCell<Option<ProgressBar>>,
+}
+
+impl ProgressBars {
+    /// The bars, unless they would get in the way of the output of `run`.
+    /// They are only drawn at the default log level: `-q` hides them, and
+    /// with `-v` the debug lines on stderr would tear them apart.
+    fn observer(&self, run: &RunOpts) -> &dyn Observer {
+        if !run.dry_run && log::max_level() == LevelFilter::Info {
+            self
+        } else {
+            &()
+        }
+    }
+}
+
+impl Observer for ProgressBars {
+    fn on_event(&self, event: &FlashEvent) {
+        match *event {
+            FlashEvent::WriteStarted { target, total } => {
+                let bar = ProgressBar::new(total as u64).with_style(
+                    ProgressStyle::with_template(
+                        "{msg:8} [{bar:40}] {bytes}/{total_bytes} {binary_bytes_per_sec} ETA {eta}",
+                    )
+                    .expect("valid progress template")
+                    .progress_chars("=> "),
+                );
+                bar.set_message(format!("{:?}", target));
+                *self.current.borrow_mut() = Some(bar);
+            }
+            FlashEvent::ChunkWritten { written, total, .. } => {
+                if let Some(bar) = &*self.current.borrow() {
+                    bar.set_position(written as u64);
+                    if written == total {
+                        bar.finish();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A device as shown by `list`.
+#[derive(Serialize)]
+struct ListedDevice {
+    #[serde(flatten)]
+    device: AP2Device,
+    /// Only asked of command interfaces in IAP mode.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    firmware: Option<Vec<FwVersion>>,
+}
+
+fn list(json: bool) {
+    let api = match HidApi::new() {
+        Ok(api) => api,
+        Err(err) => {
+            eprintln!("Unable to enumerate devices: {}", err);
+            process::exit(1);
+        }
+    };
+    let devices: Vec<ListedDevice> = device::enumerate(&api)
+        .into_iter()
+        .map(|device| {
+            let firmware = if device.mode == Mode::Iap && device.is_command_interface() {
+                Some(firmware_versions(&api, &device))
+            } else {
+                None
+            };
+            ListedDevice { device, firmware }
+        })
+        .collect();
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&devices).expect("device list is serializable")
+        );
+    } else {
+        print_devices(&devices);
+    }
+}
+
+/// Asks every MCU of a keyboard in IAP mode for its versions, leaving out
+/// the ones that do not answer.
+fn firmware_versions(api: &HidApi, dev: &AP2Device) -> Vec<FwVersion> {
+    let keyboard = match AnnePro2::open(api, dev) {
+        Ok(keyboard) => keyboard,
+        Err(err) => {
+            warn!("Unable to open {}: {}", dev.path, err);
+            return Vec::new();
+        }
+    };
+    [AP2Target::McuMain, AP2Target::McuLed, AP2Target::McuBle]
+        .iter()
+        .filter_map(|&target| match keyboard.version(target) {
+            Ok(version) => Some(version),
+            Err(err) => {
+                debug!("{:?} of {}: {}", target, dev.path, err);
+                None
+            }
+        })
+        .collect()
+}
+
+fn mode(selector: &Selector, iap: bool, timeout: u64) {
+    let mut api = match HidApi::new() {
+        Ok(api) => api,
+        Err(err) => {
+            eprintln!("Unable to enumerate devices: {}", err);
+            process::exit(1);
+        }
+    };
+
+    let result = if iap {
+        println!("Switching keyboard to IAP mode...");
+        annepro2::switch_to_iap(
+            &mut api,
+            selector,
+            Duration::from_secs(timeout),
+            &cancel_on_ctrl_c(),
+        )
+    } else {
+        device::select(&device::enumerate(&api), selector, None)
+    };
+    match result {
+        Ok(dev) => println!("{} at {} is in {} mode", dev.revision, dev.path, dev.mode),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(exit_code(&err));
+        }
+    }
+}
+
+fn version(selector: &Selector, json: bool) {
+    let keyboard = match HidApi::new()
+        .map_err(AP2FlashError::from)
+        .and_then(|api| annepro2::open_device(&api, selector))
+    {
+        Ok(keyboard) => keyboard,
+        Err(err) => {
+            eprintln!("Unable to open device: {}", err);
+            process::exit(exit_code(&err));
+        }
+    };
+
+    let mut versions = Vec::new();
+    for &target in &[AP2Target::McuMain, AP2Target::McuLed, AP2Target::McuBle] {
+        match keyboard.version(target) {
+            Ok(version) => versions.push(version),
+            Err(err) => eprintln!("{:?}: {}", target, err),
+        }
+    }
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&versions).expect("versions are serializable")
+        );
     } else {
-        println!("Flash error: {:?}", result.unwrap_err());
+        println!("{:<8} {:<10} APPLICATION", "MCU", "BOOTLOADER");
+        for version in versions {
+            println!(
+                "{:<8} {:<10} {}",
+                format!("{:?}", version.target),
+                version.bootloader,
+                version.application
+            );
+        }
+    }
+}
+
+fn print_bundle(bundle: &Bundle) {
+    let revisions: Vec<String> = bundle.revisions.iter().map(|it| it.to_string()).collect();
+    println!(
+        "Revisions: {}",
+        if revisions.is_empty() {
+            "any".to_string()
+        } else {
+            revisions.join(", ")
+        }
+    );
+    println!(
+        "{:<8} {:<12} {:<8} {:<8} CRC32",
+        "MCU", "VERSION", "ADDRESS", "SIZE"
+    );
+    for image in bundle.header().images {
+        for segment in image.segments {
+            println!(
+                "{:<8} {:<12} {:<8} {:<8} {:08x}",
+                format!("{:?}", image.target),
+                image.version.as_deref().unwrap_or("-"),
+                format!("{:#06x}", segment.address),
+                segment.size,
+                segment.crc32
+            );
+        }
+    }
+}
+
+fn print_plan(plan: &FlashPlan) {
+    println!(
+        "Plan for a {} keyboard, {} steps, about {:.1} s",
+        plan.revision,
+        plan.steps.len(),
+        plan.estimated_duration().as_secs_f64()
+    );
+    println!(
+        "{:<8} {:<8} {:<8} {:<8} {:<6} TIME",
+        "STEP", "MCU", "ADDRESS", "SIZE", "COUNT"
+    );
+    let mut index = 0;
+    while index < plan.steps.len() {
+        // Runs of chunk writes to the same MCU are shown as one line.
+        let step = &plan.steps[index];
+        let count = match step {
+            Step::Write { .. } => plan.steps[index..]
+                .iter()
+                .take_while(|it| matches!(it, Step::Write { .. }) && it.target() == step.target())
+                .count(),
+            _ => 1,
+        };
+        let group = &plan.steps[index..index + count];
+        index += count;
+
+        let (name, address) = match step {
+            Step::Erase { range, .. } => ("erase", Some(range.start)),
+            Step::Write { address, .. } => ("write", Some(*address)),
+            Step::Verify { address, .. } => ("verify", Some(*address)),
+            Step::ApFlag { .. } => ("ap-flag", None),
+            Step::Boot => ("boot", None),
+            _ => ("other", None),
+        };
+        let time: Duration = group.iter().map(|it| plan.estimate(it)).sum();
+        println!(
+            "{:<8} {:<8} {:<8} {:<8} {:<6} {} ms",
+            name,
+            step.target()
+                .map_or_else(|| "-".to_string(), |it| format!("{:?}", it)),
+            address.map_or_else(|| "-".to_string(), |it| format!("{:#06x}", it)),
+            group.iter().map(Step::size).sum::<usize>(),
+            count,
+            time.as_millis()
+        );
+    }
+}
+
+fn print_report(jobs: &[FlashJob], report: &BatchReport) {
+    if jobs.len() < 2 {
+        return;
+    }
+    println!("{:<8} {:<8} {:<8} STATUS", "MCU", "BYTES", "SEGMENTS");
+    for job in jobs {
+        let done = report.targets.iter().find(|it| it.target == job.target);
+        let status = match done {
+            Some(it) if it.verified => "verified",
+            Some(_) => "written",
+            None if report.error.is_some() => "not flashed",
+            None => "skipped",
+        };
+        println!(
+            "{:<8} {:<8} {:<8} {}",
+            format!("{:?}", job.target),
+            job.segments.iter().map(|it| it.data.len()).sum::<usize>(),
+            job.segments.len(),
+            status
+        );
+    }
+    println!(
+        "AP flag {}, {}",
+        if report.ap_flag_written {
+            "written"
+        } else {
+            "not written"
+        },
+        if report.booted {
+            "booted"
+        } else {
+            "not booted"
+        }
+    );
+}
+
+fn print_devices(devices: &[ListedDevice]) {
+    if devices.is_empty() {
+        println!("No Anne Pro 2 found.");
+        return;
+    }
+    println!(
+        "{:<5} {:<4} {:<7} {:<5} {:<8} {:<16} PATH",
+        "INDEX", "REV", "MODE", "IFACE", "RELEASE", "SERIAL"
+    );
+    let mut index = 0;
+    for ListedDevice {
+        device: dev,
+        firmware,
+    } in devices
+    {
+        // Only command interfaces get an index, matching `--index`.
+        let shown_index = if dev.is_command_interface() {
+            index += 1;
+            (index - 1).to_string()
+        } else {
+            "-".to_string()
+        };
+        println!(
+            "{:<5} {:<4} {:<7} {:<5} {:<8} {:<16} {}",
+            shown_index,
+            dev.revision,
+            dev.mode,
+            dev.interface,
+            dev.release,
+            dev.serial.as_deref().unwrap_or("-"),
+            dev.path
+        );
+        for version in firmware.iter().flatten() {
+            println!(
+                "{:<5} {:<8} bootloader {}, application {}",
+                "",
+                format!("{:?}", version.target),
+                version.bootloader,
+                version.application
+            );
+        }
     }
 }
@@ -0,0 +1,85 @@
+//! TOML manifests listing several images to flash in one session:
+//!
+//! ```toml
+//! revisions = ["C15"]
+//!
+//! [[image]]
+//! target = "main"
+//! file = "annepro2_c15.bin"
+//! version = "1.4.0"
+//!
+//! [[image]]
+//! target = "led"
+//! file = "annepro2-shine-C15.bin"
+//! base = 0x4000
+//! ```
+//!
+//! Relative paths are resolved against the manifest's directory and `base`
+//! defaults to the target's application start. `revisions`
+//! and `version` are optional and only recorded when building a bundle.
+
+use crate::bundle::{Bundle, Image};
+use crate::device::Revision;
+use crate::error::{AP2FlashError, Result};
+use crate::firmware;
+use crate::memory_map;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    revisions: Vec<Revision>,
+    #[serde(rename = "image")]
+    images: Vec<Entry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Entry {
+    target: String,
+    file: PathBuf,
+    /// Only used for raw binaries.
+    base: Option<u32>,
+    version: Option<String>,
+}
+
+/// Reads the manifest at `path` and loads every image it lists.
+pub fn load(path: &Path) -> Result<Bundle> {
+    let text = fs::read_to_string(path)?;
+    let manifest: Manifest =
+        toml::from_str(&text).map_err(|err| AP2FlashError::InvalidManifest(err.to_string()))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+    let mut images: Vec<Image> = Vec::with_capacity(manifest.images.len());
+    for entry in manifest.images {
+        let target = entry
+            .target
+            .parse()
+            .map_err(AP2FlashError::InvalidManifest)?;
+        if images.iter().any(|image| image.target == target) {
+            return Err(AP2FlashError::InvalidManifest(format!(
+                "{:?} is listed more than once",
+                target
+            )));
+        }
+        let segments = firmware::load(
+            &dir.join(&entry.file),
+            entry
+                .base
+                .unwrap_or_else(|| memory_map::default_base(target)),
+        )?;
+        images.push(Image {
+            target,
+            version: entry.version,
+            segments,
+        });
+    }
+    if images.is_empty() {
+        return Err(AP2FlashError::InvalidManifest("no images listed".into()));
+    }
+    Ok(Bundle {
+        revisions: manifest.revisions,
+        images,
+    })
+}
@@ -0,0 +1,100 @@
+//! Flash layout of every MCU on each hardware revision.
+
+use crate::annepro2::AP2Target;
+use crate::device::Revision;
+use crate::firmware::Segment;
+use std::ops::Range;
+
+const REVISIONS: [Revision; 2] = [Revision::C15, Revision::C18];
+
+/// Where the bootloader ends and how much flash is left for firmware.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryMap {
+    /// Where the application image starts and the bootloader jumps to.
+    /// Everything below belongs to the IAP bootloader and is never written.
+    pub application_start: u32,
+    /// Total flash; addresses run from 0 up to this.
+    pub flash_size: u32,
+    /// Bytes cleared by a single erase command.
+    pub page_size: u32,
+}
+
+impl MemoryMap {
+    /// The layout of `target` on a `revision` keyboard, or `None` for targets
+    /// without flash of their own.
+    pub fn of(target: AP2Target, revision: Revision) -> Option<MemoryMap> {
+        // The parts are the ones QMK (main) and Shine (LED) are built for,
+        // the sizes those of Holtek's datasheets. Main: HT32F1654, 64 KB
+        // (HT32F1653/1654/1655/1656 datasheet). LED: HT32F52342, 64 KB, on
+        // C15 and HT32F52352, 128 KB, on C18 (HT32F52342/52352 datasheet).
+        // The IAP bootloader takes the first 16 KB of both, which is why QMK
+        // and Shine link their images at 0x4000. The BLE module's layout is
+        // not documented: it is a 64 KB part, and 0x4000 is where this tool
+        // has always flashed it.
+        let (application_start, flash_size) = match (target, revision) {
+            (AP2Target::McuMain, _) => (0x4000, 0x10000),
+            (AP2Target::McuLed, Revision::C15) => (0x4000, 0x10000),
+            (AP2Target::McuLed, Revision::C18) => (0x4000, 0x20000),
+            (AP2Target::McuBle, _) => (0x4000, 0x10000),
+            _ => return None,
+        };
+        Some(MemoryMap {
+            application_start,
+            flash_size,
+            page_size: 0x400,
+        })
+    }
+
+    /// The layouts of `target` on every revision it exists on.
+    pub fn all(target: AP2Target) -> Vec<MemoryMap> {
+        REVISIONS
+            .iter()
+            .filter_map(|&revision| MemoryMap::of(target, revision))
+            .collect()
+    }
+
+    /// The part of flash firmware may be written to.
+    pub fn application_region(&self) -> Range<u32> {
+        self.application_start..self.flash_size
+    }
+
+    /// The whole pages that have to be erased before `segments` can be
+    /// written, in address order and merged into as few ranges as possible.
+    /// The segments may come in any order.
+    pub fn pages_covering(&self, segments: &[Segment]) -> Vec<Range<u32>> {
+        let mut pages: Vec<Range<u32>> = segments
+            .iter()
+            .filter(|it| !it.data.is_empty())
+            .map(|segment| {
+                let start = segment.address / self.page_size * self.page_size;
+                let end = segment
+                    .end()
+                    .unwrap_or(u32::MAX)
+                    .div_ceil(self.page_size)
+                    .saturating_mul(self.page_size);
+                start..end
+            })
+            .collect();
+        pages.sort_by_key(|it| it.start);
+        let mut ranges: Vec<Range<u32>> = Vec::new();
+        for Range { start, end } in pages {
+            match ranges.last_mut() {
+                Some(last) if last.end >= start => last.end = last.end.max(end),
+                _ => ranges.push(start..end),
+            }
+        }
+        ranges
+    }
+}
+
+/// Where a raw binary for `target` goes unless told otherwise: its
+/// application start, the highest one if revisions differ, so the image
+/// clears the bootloader on every revision. Targets without flash of their
+/// own fall back to 0x4000.
+pub fn default_base(target: AP2Target) -> u32 {
+    MemoryMap::all(target)
+        .iter()
+        .map(|map| map.application_start)
+        .max()
+        .unwrap_or(0x4000)
+}
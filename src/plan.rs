@@ -0,0 +1,546 @@
+//! Working out every command of a flash session before any is sent.
+//!
+//! A [`FlashPlan`] lists the erases, chunk writes, read-backs, the AP flag
+//! and the boot of a session in the order they will happen. It can be
+//! reviewed, saved as JSON, checked with [`FlashPlan::validate`] and then
+//! run against a keyboard, the emulator or a recording.
+
+use crate::annepro2::{self, AP2Target, BatchReport, FlashJob, FlashOptions, TargetReport};
+use crate::cancel::CancelToken;
+use crate::device::Revision;
+use crate::error::{AP2FlashError, Result};
+use crate::events::{FlashEvent, Observer};
+use crate::firmware::{self, Segment};
+use crate::memory_map::MemoryMap;
+use crate::record::{from_hex, to_hex};
+use crate::session::AnnePro2;
+use crate::transport::Transport;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+use std::time::Duration;
+
+/// Rough time for one command and its reply to cross the USB bus.
+const ROUND_TRIP: Duration = Duration::from_millis(2);
+/// Rough time the bootloader needs to erase one page of flash.
+const PAGE_ERASE: Duration = Duration::from_millis(20);
+
+/// Every step of a flash session for a keyboard of `revision`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlashPlan {
+    pub revision: Revision,
+    pub steps: Vec<Step>,
+}
+
+/// One step of a [`FlashPlan`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum Step {
+    /// Erase the whole pages in `range`, one command per page.
+    Erase {
+        target: AP2Target,
+        range: Range<u32>,
+    },
+    /// Write a single chunk at `address`; `data` is hex.
+    Write {
+        target: AP2Target,
+        address: u32,
+        data: String,
+    },
+    /// Read `len` bytes at `address` back and compare them with what the
+    /// plan wrote there.
+    Verify {
+        target: AP2Target,
+        address: u32,
+        len: usize,
+    },
+    /// Mark the application as valid.
+    ApFlag { flag: u8 },
+    /// Start the application.
+    Boot,
+}
+
+impl Step {
+    /// The MCU the step is sent to, `None` for steps about the keyboard as a
+    /// whole.
+    pub fn target(&self) -> Option<AP2Target> {
+        match self {
+            Step::Erase { target, .. }
+            | Step::Write { target, .. }
+            | Step::Verify { target, .. } => Some(*target),
+            Step::ApFlag { .. } | Step::Boot => None,
+        }
+    }
+
+    /// Bytes erased, written or read back.
+    pub fn size(&self) -> usize {
+        match self {
+            Step::Erase { range, .. } => (range.end - range.start) as usize,
+            Step::Write { data, .. } => data.len() / 2,
+            Step::Verify { len, .. } => *len,
+            Step::ApFlag { .. } | Step::Boot => 0,
+        }
+    }
+}
+
+impl FlashPlan {
+    /// Plans flashing `jobs` onto a keyboard of `revision`. The erase, verify
+    /// and boot settings of `options` are fixed in the plan; the rest only
+    /// matter once it is run. The plan is validated before it is returned.
+    pub fn new(jobs: &[FlashJob], revision: Revision, options: &FlashOptions) -> Result<Self> {
+        annepro2::check_jobs(jobs)?;
+        annepro2::check_revision(jobs, revision, options)?;
+
+        let mut steps = Vec::new();
+        for job in jobs {
+            let target = job.target;
+            let segments = firmware::normalize(job.segments.clone())?;
+            let map = memory_map(target, revision)?;
+            let ranges = if options.full_erase {
+                vec![map.application_region()]
+            } else {
+                map.pages_covering(&segments)
+            };
+            steps.extend(
+                ranges
+                    .into_iter()
+                    .map(|range| Step::Erase { target, range }),
+            );
+            for segment in &segments {
+                let mut address = segment.address;
+                for chunk in segment.data.chunks(annepro2::chunk_size(target)) {
+                    steps.push(Step::Write {
+                        target,
+                        address,
+                        data: to_hex(chunk),
+                    });
+                    address += chunk.len() as u32;
+                }
+            }
+            if options.verify {
+                steps.extend(segments.iter().map(|segment| Step::Verify {
+                    target,
+                    address: segment.address,
+                    len: segment.data.len(),
+                }));
+            }
+        }
+        steps.push(Step::ApFlag { flag: 2 });
+        if options.boot {
+            steps.push(Step::Boot);
+        }
+        let plan = FlashPlan { revision, steps };
+        plan.validate()?;
+        Ok(plan)
+    }
+
+    /// Parses a plan saved with [`to_json`](Self::to_json) and validates it.
+    pub fn parse(text: &str) -> Result<Self> {
+        let plan: FlashPlan = serde_json::from_str(text)
+            .map_err(|err| AP2FlashError::InvalidPlan(err.to_string()))?;
+        plan.validate()?;
+        Ok(plan)
+    }
+
+    /// The plan as pretty printed JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("plan is serializable")
+    }
+
+    /// Checks the plan could have come from [`new`](Self::new): everything
+    /// stays inside the application regions of this revision, chunks are
+    /// only written to erased flash and never twice, read-backs only cover
+    /// written data, and the AP flag is written once, after everything else
+    /// but booting.
+    pub fn validate(&self) -> Result<()> {
+        let mut erased: HashSet<(AP2Target, u32)> = HashSet::new();
+        let mut written: Vec<(AP2Target, Range<u32>)> = Vec::new();
+        let mut ap_flag = false;
+        let mut booted = false;
+        for (index, step) in self.steps.iter().enumerate() {
+            let invalid = |reason: &str| {
+                AP2FlashError::InvalidPlan(format!("step {}: {}", index + 1, reason))
+            };
+            if booted {
+                return Err(invalid("nothing can follow booting"));
+            }
+            if ap_flag && matches!(step, Step::Erase { .. } | Step::Write { .. }) {
+                return Err(invalid("flash is changed after the AP flag was written"));
+            }
+            match step {
+                Step::Erase { target, range } => {
+                    let map = memory_map(*target, self.revision)?;
+                    let region = map.application_region();
+                    if range.start >= range.end
+                        || range.start % map.page_size != 0
+                        || range.end % map.page_size != 0
+                    {
+                        return Err(invalid("erase range is not made of whole pages"));
+                    }
+                    if range.start < region.start || range.end > region.end {
+                        return Err(AP2FlashError::OutsideApplicationRegion {
+                            target: *target,
+                            start: range.start,
+                            end: range.end,
+                        });
+                    }
+                    if written
+                        .iter()
+                        .any(|(t, it)| t == target && overlaps(it, range))
+                    {
+                        return Err(invalid("erases data written by an earlier step"));
+                    }
+                    for page in range.clone().step_by(map.page_size as usize) {
+                        erased.insert((*target, page));
+                    }
+                }
+                Step::Write {
+                    target,
+                    address,
+                    data,
+                } => {
+                    let data = from_hex(data)
+                        .filter(|it| !it.is_empty())
+                        .ok_or_else(|| invalid("chunk data is not valid hex"))?;
+                    if data.len() > annepro2::chunk_size(*target) {
+                        return Err(invalid("chunk is larger than one write command"));
+                    }
+                    let segment = Segment {
+                        address: *address,
+                        data,
+                    };
+                    let end = segment
+                        .end()
+                        .ok_or_else(|| invalid("chunk runs past the end of the address space"))?;
+                    firmware::check_fits(
+                        std::slice::from_ref(&segment),
+                        *target,
+                        Some(self.revision),
+                    )?;
+                    let page_size = memory_map(*target, self.revision)?.page_size;
+                    let first_page = segment.address / page_size * page_size;
+                    if !(first_page..end)
+                        .step_by(page_size as usize)
+                        .all(|page| erased.contains(&(*target, page)))
+                    {
+                        return Err(invalid("writes to flash that was not erased"));
+                    }
+                    let range = segment.address..end;
+                    if written
+                        .iter()
+                        .any(|(t, it)| t == target && overlaps(it, &range))
+                    {
+                        return Err(invalid("overwrites data written by an earlier step"));
+                    }
+                    written.push((*target, range));
+                }
+                Step::Verify {
+                    target,
+                    address,
+                    len,
+                } => {
+                    if *len == 0
+                        || expected(&self.steps[..index], *target, *address, *len).is_none()
+                    {
+                        return Err(invalid("reads back data the plan did not write"));
+                    }
+                }
+                Step::ApFlag { .. } if ap_flag => {
+                    return Err(invalid("the AP flag is written twice"));
+                }
+                Step::ApFlag { .. } => ap_flag = true,
+                Step::Boot if !ap_flag => {
+                    return Err(invalid("booting before the AP flag is written"));
+                }
+                Step::Boot => booted = true,
+            }
+        }
+        if written.is_empty() {
+            return Err(AP2FlashError::InvalidPlan("nothing is written".into()));
+        }
+        if !ap_flag {
+            return Err(AP2FlashError::InvalidPlan(
+                "the AP flag is never written".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The images the plan writes, one job per MCU with touching chunks
+    /// merged into segments.
+    pub fn jobs(&self) -> Vec<FlashJob> {
+        let mut jobs: Vec<FlashJob> = Vec::new();
+        for step in &self.steps {
+            if let Step::Write {
+                target,
+                address,
+                data,
+            } = step
+            {
+                let data = from_hex(data).unwrap_or_default();
+                let index = match jobs.iter().position(|job| job.target == *target) {
+                    Some(index) => index,
+                    None => {
+                        jobs.push(FlashJob {
+                            target: *target,
+                            segments: Vec::new(),
+                        });
+                        jobs.len() - 1
+                    }
+                };
+                let segments = &mut jobs[index].segments;
+                match segments.last_mut() {
+                    Some(segment) if segment.end() == Some(*address) => segment.data.extend(data),
+                    _ => segments.push(Segment {
+                        address: *address,
+                        data,
+                    }),
+                }
+            }
+        }
+        jobs
+    }
+
+    /// A rough guess at how long `step` takes on a real keyboard.
+    pub fn estimate(&self, step: &Step) -> Duration {
+        match step {
+            Step::Erase { target, range } => {
+                let page_size =
+                    MemoryMap::of(*target, self.revision).map_or(0x400, |it| it.page_size);
+                let pages = (range.end - range.start).div_ceil(page_size);
+                (PAGE_ERASE + ROUND_TRIP) * pages
+            }
+            Step::Verify { target, len, .. } => {
+                ROUND_TRIP * len.div_ceil(annepro2::chunk_size(*target)) as u32
+            }
+            Step::Write { .. } | Step::ApFlag { .. } | Step::Boot => ROUND_TRIP,
+        }
+    }
+
+    /// A rough guess at how long the whole plan takes on a real keyboard.
+    pub fn estimated_duration(&self) -> Duration {
+        self.steps.iter().map(|step| self.estimate(step)).sum()
+    }
+
+    /// Validates the plan and runs it over an already opened `handle` to a
+    /// keyboard of the plan's revision, see [`flash_plan`] for running it
+    /// against a connected keyboard. Only the retry and record settings of
+    /// `options` are used.
+    ///
+    /// [`flash_plan`]: crate::annepro2::flash_plan
+    pub fn execute<T: Transport>(
+        &self,
+        handle: &T,
+        options: &FlashOptions,
+        observer: &dyn Observer,
+        cancel: &CancelToken,
+    ) -> BatchReport {
+        let mut report = BatchReport::default();
+        if let Err(err) = self.validate().and_then(|()| {
+            annepro2::run_recorded(handle, self, options, observer, cancel, &mut report)
+        }) {
+            report.error = Some(err);
+        }
+        report
+    }
+
+    /// Sends every step over `session`, recording what was done in `report`.
+    /// A target only shows up in the report once all its steps succeeded.
+    pub(crate) fn run<T: Transport>(
+        &self,
+        session: &AnnePro2<T>,
+        observer: &dyn Observer,
+        report: &mut BatchReport,
+    ) -> Result<()> {
+        annepro2::reset_unrecognised_reply_warning();
+        let cancel = session.cancel_token();
+        let mut current: Option<TargetReport> = None;
+        let mut progress = TargetProgress {
+            inner: observer,
+            written_before: 0,
+            total: 0,
+        };
+        let mut next_address = 0;
+        for step in &self.steps {
+            // Checked before the AP flag too: marking a half written image
+            // as valid would brick the keyboard, so a late cancel still wins.
+            cancel.check()?;
+            if current.as_ref().map(|it| it.target) != step.target() {
+                report.targets.extend(current.take());
+                current = step.target().map(|target| TargetReport {
+                    target,
+                    bytes: 0,
+                    segments: 0,
+                    verified: false,
+                });
+            }
+            match step {
+                Step::Erase { target, range } => {
+                    report.flash_modified = true;
+                    observer.on_event(&FlashEvent::EraseStarted {
+                        target: *target,
+                        range: range.clone(),
+                    });
+                    session.erase(*target, range.clone())?;
+                    debug!("Erased {:#08x}..{:#08x}", range.start, range.end);
+                    observer.on_event(&FlashEvent::EraseFinished {
+                        target: *target,
+                        range: range.clone(),
+                    });
+                }
+                Step::Write {
+                    target,
+                    address,
+                    data,
+                } => {
+                    let data = from_hex(data).ok_or_else(|| {
+                        AP2FlashError::InvalidPlan(format!("bad chunk data at {:#08x}", address))
+                    })?;
+                    let done = current.as_mut().expect("write steps have a target");
+                    if done.bytes == 0 {
+                        progress.written_before = 0;
+                        progress.total = self.bytes_written(*target);
+                        observer.on_event(&FlashEvent::WriteStarted {
+                            target: *target,
+                            total: progress.total,
+                        });
+                    }
+                    report.flash_modified = true;
+                    session.write_observed(*target, *address, &data, &progress)?;
+                    if done.segments == 0 || next_address != *address {
+                        done.segments += 1;
+                    }
+                    done.bytes += data.len();
+                    progress.written_before += data.len();
+                    next_address = *address + data.len() as u32;
+                }
+                Step::Verify {
+                    target,
+                    address,
+                    len,
+                } => {
+                    let data = expected(&self.steps, *target, *address, *len).ok_or_else(|| {
+                        AP2FlashError::InvalidPlan(format!("nothing written at {:#08x}", address))
+                    })?;
+                    session.verify(*target, *address, &data)?;
+                    info!("Verified {} bytes at {:#08x}", len, address);
+                    observer.on_event(&FlashEvent::Verified {
+                        target: *target,
+                        address: *address,
+                        len: *len,
+                    });
+                    current
+                        .as_mut()
+                        .expect("verify steps have a target")
+                        .verified = true;
+                }
+                Step::ApFlag { flag } => {
+                    session.set_ap_flag(*flag)?;
+                    report.ap_flag_written = true;
+                    observer.on_event(&FlashEvent::ApFlagWritten { flag: *flag });
+                }
+                Step::Boot => {
+                    session.boot()?;
+                    report.booted = true;
+                    observer.on_event(&FlashEvent::Booted);
+                }
+            }
+        }
+        report.targets.extend(current);
+        Ok(())
+    }
+
+    fn bytes_written(&self, target: AP2Target) -> usize {
+        self.steps
+            .iter()
+            .filter(|step| matches!(step, Step::Write { .. }) && step.target() == Some(target))
+            .map(Step::size)
+            .sum()
+    }
+}
+
+/// Turns the per-chunk counts of [`FlashEvent::ChunkWritten`] into counts
+/// for everything written to the target.
+struct TargetProgress<'a> {
+    inner: &'a dyn Observer,
+    written_before: usize,
+    total: usize,
+}
+
+impl Observer for TargetProgress<'_> {
+    fn on_event(&self, event: &FlashEvent) {
+        match *event {
+            FlashEvent::ChunkWritten {
+                target,
+                address,
+                len,
+                written,
+                ..
+            } => self.inner.on_event(&FlashEvent::ChunkWritten {
+                target,
+                address,
+                len,
+                written: self.written_before + written,
+                total: self.total,
+            }),
+            _ => self.inner.on_event(event),
+        }
+    }
+}
+
+/// Reads a plan saved with [`write()`], validating it.
+pub fn read(path: &Path) -> Result<FlashPlan> {
+    FlashPlan::parse(&fs::read_to_string(path)?)
+}
+
+/// Validates `plan` and writes it to `path` as JSON.
+pub fn write(path: &Path, plan: &FlashPlan) -> Result<()> {
+    plan.validate()?;
+    fs::write(path, plan.to_json())?;
+    Ok(())
+}
+
+fn memory_map(target: AP2Target, revision: Revision) -> Result<MemoryMap> {
+    MemoryMap::of(target, revision)
+        .ok_or_else(|| AP2FlashError::InvalidImage(format!("{:?} cannot be flashed", target)))
+}
+
+fn overlaps(a: &Range<u32>, b: &Range<u32>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// The bytes `steps` write to `len` bytes at `address`, `None` unless every
+/// one of them is written.
+fn expected(steps: &[Step], target: AP2Target, address: u32, len: usize) -> Option<Vec<u8>> {
+    let wanted = address..address.checked_add(len as u32)?;
+    let mut data = vec![0xffu8; len];
+    let mut covered = 0;
+    for step in steps {
+        if let Step::Write {
+            target: t,
+            address: start,
+            data: chunk,
+        } = step
+        {
+            let chunk = from_hex(chunk)?;
+            let end = *start + chunk.len() as u32;
+            if *t != target || !overlaps(&(*start..end), &wanted) {
+                continue;
+            }
+            let from = (*start).max(wanted.start);
+            let to = end.min(wanted.end);
+            data[(from - wanted.start) as usize..(to - wanted.start) as usize]
+                .copy_from_slice(&chunk[(from - start) as usize..(to - start) as usize]);
+            covered += (to - from) as usize;
+        }
+    }
+    if covered == len {
+        Some(data)
+    } else {
+        None
+    }
+}
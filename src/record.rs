@@ -0,0 +1,201 @@
+//! Capturing the reports of a session to a file and feeding them back.
+//!
+//! A recording is one JSON object per line, e.g.
+//! `{"elapsed_us":1520,"direction":"write","data":"007b10...","error":null}`.
+
+use crate::error::{AP2FlashError, Result};
+use crate::transport::Transport;
+use hidapi::{HidError, HidResult};
+use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+/// Whether a report went to the keyboard or came from it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Write,
+    Read,
+}
+
+/// One report that went to or came from the keyboard.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Event {
+    /// Microseconds since the recording started.
+    pub elapsed_us: u64,
+    pub direction: Direction,
+    /// The report as hex; empty for a read that timed out or failed.
+    pub data: String,
+    /// Set if the operation failed instead.
+    pub error: Option<String>,
+}
+
+/// Passes everything through to `inner` and logs it to `out`.
+pub struct Recorder<T, W: Write> {
+    inner: T,
+    out: RefCell<W>,
+    start: Instant,
+}
+
+impl<T: Transport> Recorder<T, BufWriter<File>> {
+    /// Records to a new file at `path`, replacing any existing one.
+    pub fn create(path: &Path, inner: T) -> Result<Self> {
+        Ok(Recorder::new(inner, BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<T: Transport, W: Write> Recorder<T, W> {
+    /// Records everything passing through `inner` to `out`.
+    pub fn new(inner: T, out: W) -> Self {
+        Recorder {
+            inner,
+            out: RefCell::new(out),
+            start: Instant::now(),
+        }
+    }
+
+    /// Gives back the sink the recording was written to.
+    pub fn into_output(self) -> W {
+        self.out.into_inner()
+    }
+
+    fn log(&self, direction: Direction, data: &[u8], error: Option<&HidError>) -> HidResult<()> {
+        let event = Event {
+            elapsed_us: self.start.elapsed().as_micros() as u64,
+            direction,
+            data: to_hex(data),
+            error: error.map(HidError::to_string),
+        };
+        let line = serde_json::to_string(&event).expect("event is serializable");
+        let mut out = self.out.borrow_mut();
+        writeln!(out, "{}", line)
+            .and_then(|()| out.flush())
+            .map_err(|error| HidError::IoError { error })
+    }
+}
+
+impl<T: Transport, W: Write> Transport for Recorder<T, W> {
+    fn write_report(&self, report: &[u8]) -> HidResult<usize> {
+        let result = self.inner.write_report(report);
+        self.log(Direction::Write, report, result.as_ref().err())?;
+        result
+    }
+
+    fn read_report(&self, buf: &mut [u8], timeout_ms: i32) -> HidResult<usize> {
+        let result = self.inner.read_report(buf, timeout_ms);
+        match &result {
+            Ok(len) => self.log(Direction::Read, &buf[..*len], None)?,
+            Err(err) => self.log(Direction::Read, &[], Some(err))?,
+        }
+        result
+    }
+}
+
+/// Plays a recording back. Writes must match the recorded ones byte for
+/// byte, reads return what was recorded, so a session can be reproduced
+/// without a keyboard.
+pub struct Replay {
+    events: RefCell<VecDeque<Event>>,
+    position: Cell<usize>,
+}
+
+impl Replay {
+    /// Plays `events` back in order.
+    pub fn new(events: Vec<Event>) -> Self {
+        Replay {
+            events: RefCell::new(events.into()),
+            position: Cell::new(0),
+        }
+    }
+
+    /// Reads a recording written by [`Recorder`].
+    pub fn load(path: &Path) -> Result<Self> {
+        Replay::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses the text of a recording written by [`Recorder`].
+    pub fn parse(text: &str) -> Result<Self> {
+        let events = text
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .enumerate()
+            .map(|(i, line)| {
+                serde_json::from_str(line).map_err(|err| {
+                    AP2FlashError::InvalidRecording(format!("line {}: {}", i + 1, err))
+                })
+            })
+            .collect::<Result<Vec<Event>>>()?;
+        Ok(Replay::new(events))
+    }
+
+    /// Whether every recorded event has been played back.
+    pub fn is_finished(&self) -> bool {
+        self.events.borrow().is_empty()
+    }
+
+    fn next(&self, direction: Direction) -> HidResult<Event> {
+        let position = self.position.get() + 1;
+        self.position.set(position);
+        match self.events.borrow_mut().pop_front() {
+            Some(event) if event.direction == direction => Ok(event),
+            Some(event) => Err(replay_error(format!(
+                "event {} is a {:?}, not a {:?}",
+                position, event.direction, direction
+            ))),
+            None => Err(replay_error(format!(
+                "recording ended before event {}",
+                position
+            ))),
+        }
+    }
+}
+
+impl Transport for Replay {
+    fn write_report(&self, report: &[u8]) -> HidResult<usize> {
+        let event = self.next(Direction::Write)?;
+        if let Some(error) = event.error {
+            return Err(replay_error(error));
+        }
+        if event.data != to_hex(report) {
+            return Err(replay_error(format!(
+                "event {} differs from the recording",
+                self.position.get()
+            )));
+        }
+        Ok(report.len())
+    }
+
+    fn read_report(&self, buf: &mut [u8], _timeout_ms: i32) -> HidResult<usize> {
+        let event = self.next(Direction::Read)?;
+        if let Some(error) = event.error {
+            return Err(replay_error(error));
+        }
+        let data = from_hex(&event.data).ok_or_else(|| {
+            replay_error(format!("event {} is not valid hex", self.position.get()))
+        })?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+}
+
+/// Lower case hex without separators, as stored in recordings.
+pub(crate) fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes [`to_hex`] output, `None` if it is not valid hex.
+pub(crate) fn from_hex(text: &str) -> Option<Vec<u8>> {
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn replay_error(message: String) -> HidError {
+    HidError::HidApiError { message }
+}
@@ -0,0 +1,56 @@
+//! Decoding of the replies the keyboard sends back for every command.
+//!
+//! The layout and status codes are inferred rather than taken from a
+//! capture of a real keyboard; they are what the emulator speaks. Replies
+//! that do not fit are therefore not treated as failures on their own, see
+//! `write_to_target`.
+
+/// The command was carried out.
+pub const STATUS_OK: u8 = 0x00;
+/// The address is outside the MCU's flash.
+pub const STATUS_BAD_ADDRESS: u8 = 0x01;
+/// The payload was too short or too long for the command.
+pub const STATUS_BAD_LENGTH: u8 = 0x02;
+/// The command is not implemented by this bootloader.
+pub const STATUS_UNSUPPORTED: u8 = 0xff;
+
+const HEADER_SIZE: usize = 8;
+
+/// A decoded reply frame:
+/// `7b 10 <dst|src> 10 <len> 00 00 7d <l2> <key> <status> <payload...>`
+#[derive(Debug, Clone)]
+pub struct Response {
+    /// Low nibble of the address byte, the MCU that answered.
+    pub source: u8,
+    pub l2_command: u8,
+    pub key_command: u8,
+    pub status: u8,
+    /// Whatever follows the status byte, trimmed to the advertised length.
+    pub payload: Vec<u8>,
+}
+
+impl Response {
+    /// Decodes a raw report, returning `None` if it is not a valid frame.
+    pub fn parse(buf: &[u8]) -> Option<Response> {
+        if buf.len() < HEADER_SIZE || buf[0] != 0x7b || buf[1] != 0x10 || buf[7] != 0x7d {
+            return None;
+        }
+        let len = buf[4] as usize;
+        let body = buf.get(HEADER_SIZE..HEADER_SIZE + len)?;
+        if body.len() < 3 {
+            return None;
+        }
+        Some(Response {
+            source: buf[2] & 0xf,
+            l2_command: body[0],
+            key_command: body[1],
+            status: body[2],
+            payload: body[3..].to_vec(),
+        })
+    }
+
+    /// Whether the device carried the command out.
+    pub fn is_success(&self) -> bool {
+        self.status == STATUS_OK
+    }
+}
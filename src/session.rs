@@ -0,0 +1,159 @@
+//! An opened keyboard and the operations that can be run on it.
+
+use crate::annepro2::{self, AP2Target, RetryPolicy};
+use crate::cancel::CancelToken;
+use crate::device::{AP2Device, Mode, Revision};
+use crate::error::{AP2FlashError, Result};
+use crate::events::Observer;
+use crate::memory_map::MemoryMap;
+use crate::transport::Transport;
+use crate::version::FwVersion;
+use hidapi::{HidApi, HidDevice};
+use std::cell::Cell;
+use std::ops::Range;
+
+/// A connection to one Anne Pro 2. Any number of commands can be sent over
+/// it without enumerating devices again.
+pub struct AnnePro2<T = HidDevice> {
+    handle: T,
+    revision: Revision,
+    mode: Mode,
+    retry: RetryPolicy,
+    cancel: CancelToken,
+    /// Cleared the first time the bootloader fails to answer
+    /// `IapReadMemory`, after which verification goes straight to checksums.
+    read_memory: Cell<bool>,
+}
+
+impl AnnePro2<HidDevice> {
+    /// Opens `dev`, as found by [`device::enumerate`](crate::device::enumerate).
+    pub fn open(api: &HidApi, dev: &AP2Device) -> Result<Self> {
+        let handle = api.open_path(dev.info().path())?;
+        handle.set_blocking_mode(true)?;
+        Ok(AnnePro2::new(handle, dev.revision, dev.mode))
+    }
+}
+
+impl<T: Transport> AnnePro2<T> {
+    /// Wraps an already open `handle` to a keyboard of `revision` that is
+    /// running in `mode`.
+    pub fn new(handle: T, revision: Revision, mode: Mode) -> Self {
+        AnnePro2 {
+            handle,
+            revision,
+            mode,
+            retry: RetryPolicy::default(),
+            cancel: CancelToken::new(),
+            read_memory: Cell::new(true),
+        }
+    }
+
+    /// Sets how failed chunk writes are retried.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Makes erasing and writing stop between commands once `cancel` is
+    /// cancelled, failing with [`AP2FlashError::Cancelled`].
+    pub fn with_cancel_token(mut self, cancel: CancelToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    /// The token checked between erase and write commands.
+    pub fn cancel_token(&self) -> &CancelToken {
+        &self.cancel
+    }
+
+    /// The underlying transport.
+    pub fn handle(&self) -> &T {
+        &self.handle
+    }
+
+    /// The hardware revision, told apart by product id.
+    pub fn revision(&self) -> Revision {
+        self.revision
+    }
+
+    /// The mode the keyboard was in when it was opened.
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Whether the bootloader has not yet failed to read memory back.
+    pub fn supports_read_memory(&self) -> bool {
+        self.read_memory.get()
+    }
+
+    /// The flash layout of `target` on this keyboard.
+    pub fn memory_map(&self, target: AP2Target) -> Result<MemoryMap> {
+        MemoryMap::of(target, self.revision)
+            .ok_or_else(|| AP2FlashError::InvalidImage(format!("{:?} cannot be flashed", target)))
+    }
+
+    /// Erases every page that `range` touches on `target`.
+    pub fn erase(&self, target: AP2Target, range: Range<u32>) -> Result<()> {
+        let page_size = self.memory_map(target)?.page_size;
+        let start = range.start / page_size * page_size;
+        let end = range.end.div_ceil(page_size) * page_size;
+        annepro2::erase_range(&self.handle, target, start..end, page_size, &self.cancel)
+    }
+
+    /// Writes `data` to already erased flash of `target` at `address`.
+    pub fn write(&self, target: AP2Target, address: u32, data: &[u8]) -> Result<()> {
+        self.write_observed(target, address, data, &())
+    }
+
+    /// Like [`write`](Self::write), reporting every chunk and retry to
+    /// `observer`.
+    pub fn write_observed(
+        &self,
+        target: AP2Target,
+        address: u32,
+        data: &[u8],
+        observer: &dyn Observer,
+    ) -> Result<()> {
+        annepro2::flash_file(
+            &self.handle,
+            target,
+            address,
+            data,
+            &self.retry,
+            observer,
+            &self.cancel,
+        )
+    }
+
+    /// Checks that flash of `target` at `address` holds `data`.
+    pub fn verify(&self, target: AP2Target, address: u32, data: &[u8]) -> Result<()> {
+        annepro2::verify_file_with(
+            &self.handle,
+            target,
+            address,
+            &mut &data[..],
+            &self.read_memory,
+        )
+    }
+
+    /// Sets the flag marking the application as valid.
+    pub fn set_ap_flag(&self, flag: u8) -> Result<()> {
+        annepro2::write_ap_flag(&self.handle, flag)
+    }
+
+    /// Starts the application. The keyboard resets, so nothing else can be
+    /// sent over this handle afterwards.
+    pub fn boot(&self) -> Result<()> {
+        annepro2::boot_device(&self.handle)
+    }
+
+    /// Asks `target` for its bootloader and application versions.
+    pub fn version(&self, target: AP2Target) -> Result<FwVersion> {
+        annepro2::get_fw_version(&self.handle, target)
+    }
+
+    /// Asks the keyboard which mode it is running in right now.
+    pub fn query_mode(&self) -> Result<Mode> {
+        annepro2::get_mode(&self.handle)
+    }
+}
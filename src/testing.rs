@@ -0,0 +1,8 @@
+//! Stand-ins for a real keyboard, for testing code built on this crate.
+//!
+//! [`Emulator`] answers the IAP protocol in process and keeps the resulting
+//! flash contents, so a whole session can be run through
+//! [`flash_session`](crate::flash_session) or an [`AnnePro2`](crate::AnnePro2)
+//! and its outcome inspected without hardware.
+
+pub use crate::emulator::Emulator;
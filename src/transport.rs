@@ -0,0 +1,38 @@
+//! The report-level channel the protocol code talks through.
+
+use hidapi::{HidDevice, HidResult};
+
+/// A raw report-level channel to an Anne Pro 2.
+///
+/// The protocol code in [`crate::annepro2`] only ever needs to push a report
+/// out and wait for a reply, so anything that can do that (a real HID handle,
+/// a mock, a recorded session) can be used to drive a keyboard.
+pub trait Transport {
+    /// Write a single report. The first byte is the report id.
+    fn write_report(&self, report: &[u8]) -> HidResult<usize>;
+
+    /// Read a single report into `buf`, waiting at most `timeout_ms`
+    /// milliseconds (-1 waits forever). Returns the number of bytes read,
+    /// which is 0 if the timeout expired.
+    fn read_report(&self, buf: &mut [u8], timeout_ms: i32) -> HidResult<usize>;
+}
+
+impl Transport for HidDevice {
+    fn write_report(&self, report: &[u8]) -> HidResult<usize> {
+        self.write(report)
+    }
+
+    fn read_report(&self, buf: &mut [u8], timeout_ms: i32) -> HidResult<usize> {
+        self.read_timeout(buf, timeout_ms)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for &T {
+    fn write_report(&self, report: &[u8]) -> HidResult<usize> {
+        (**self).write_report(report)
+    }
+
+    fn read_report(&self, buf: &mut [u8], timeout_ms: i32) -> HidResult<usize> {
+        (**self).read_report(buf, timeout_ms)
+    }
+}
@@ -0,0 +1,64 @@
+//! Firmware versions as reported by `IapGetFwVersion`.
+
+use crate::annepro2::AP2Target;
+use serde::Serialize;
+use std::fmt;
+
+/// A `major.minor.patch` firmware version.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl Version {
+    pub fn new(major: u8, minor: u8, patch: u8) -> Self {
+        Version {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&format!("{}.{}.{}", self.major, self.minor, self.patch))
+    }
+}
+
+/// The bootloader and application versions of a single MCU.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub struct FwVersion {
+    pub target: AP2Target,
+    pub bootloader: Version,
+    pub application: Version,
+}
+
+impl FwVersion {
+    /// Decodes a reply payload laid out as bootloader major, minor, patch
+    /// followed by application major, minor, patch.
+    pub fn parse(target: AP2Target, payload: &[u8]) -> Option<FwVersion> {
+        match payload.get(..6)? {
+            [b_major, b_minor, b_patch, a_major, a_minor, a_patch] => Some(FwVersion {
+                target,
+                bootloader: Version::new(*b_major, *b_minor, *b_patch),
+                application: Version::new(*a_major, *a_minor, *a_patch),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The inverse of [`FwVersion::parse`].
+    pub fn to_bytes(&self) -> [u8; 6] {
+        [
+            self.bootloader.major,
+            self.bootloader.minor,
+            self.bootloader.patch,
+            self.application.major,
+            self.application.minor,
+            self.application.patch,
+        ]
+    }
+}
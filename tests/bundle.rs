@@ -0,0 +1,124 @@
+use annepro2_tools::bundle::{Bundle, Image};
+use annepro2_tools::{AP2FlashError, AP2Target, Revision, Segment};
+
+fn bundle() -> Bundle {
+    Bundle {
+        revisions: vec![Revision::C15],
+        images: vec![
+            Image {
+                target: AP2Target::McuMain,
+                version: Some("1.2.3".into()),
+                segments: vec![
+                    Segment {
+                        address: 0x4000,
+                        data: vec![1; 100],
+                    },
+                    Segment {
+                        address: 0x5000,
+                        data: vec![2; 10],
+                    },
+                ],
+            },
+            Image {
+                target: AP2Target::McuLed,
+                version: None,
+                segments: vec![Segment {
+                    address: 0x4000,
+                    data: vec![3; 50],
+                }],
+            },
+        ],
+    }
+}
+
+fn invalid_bundle(bytes: &[u8]) -> String {
+    match Bundle::parse(bytes) {
+        Err(AP2FlashError::InvalidBundle(reason)) => reason,
+        other => panic!("expected InvalidBundle, got {:?}", other),
+    }
+}
+
+#[test]
+fn round_trips() {
+    let parsed = Bundle::parse(&bundle().to_bytes()).unwrap();
+
+    assert_eq!(parsed.revisions, [Revision::C15]);
+    assert_eq!(parsed.images.len(), 2);
+    for (parsed, original) in parsed.images.iter().zip(&bundle().images) {
+        assert_eq!(parsed.target, original.target);
+        assert_eq!(parsed.version, original.version);
+        assert_eq!(parsed.segments, original.segments);
+    }
+}
+
+#[test]
+fn sorts_segments_stored_out_of_order() {
+    let mut bundle = bundle();
+    bundle.images[0].segments.reverse();
+    let parsed = Bundle::parse(&bundle.to_bytes()).unwrap();
+
+    let addresses: Vec<_> = parsed.images[0]
+        .segments
+        .iter()
+        .map(|it| it.address)
+        .collect();
+    assert_eq!(addresses, [0x4000, 0x5000]);
+}
+
+#[test]
+fn rejects_corrupted_image_data() {
+    let mut bytes = bundle().to_bytes();
+    // The LED image is the last one, so this lands in its only segment.
+    *bytes.last_mut().unwrap() ^= 0xff;
+
+    assert_eq!(
+        invalid_bundle(&bytes),
+        "checksum mismatch in McuLed image at 0x4000"
+    );
+}
+
+#[test]
+fn rejects_trailing_data() {
+    let mut bytes = bundle().to_bytes();
+    bytes.push(0);
+
+    assert_eq!(invalid_bundle(&bytes), "trailing data after the last image");
+}
+
+#[test]
+fn rejects_truncated_bundles() {
+    let bytes = bundle().to_bytes();
+
+    assert_eq!(
+        invalid_bundle(&bytes[..bytes.len() - 1]),
+        "truncated image data"
+    );
+    assert_eq!(invalid_bundle(&bytes[..20]), "truncated header");
+    assert_eq!(invalid_bundle(&bytes[..10]), "not a firmware bundle");
+}
+
+#[test]
+fn rejects_other_files_and_format_versions() {
+    assert_eq!(
+        invalid_bundle(b"\x7fELF\x01\x01\x01\x00\x00\x00\x00\x00"),
+        "not a firmware bundle"
+    );
+
+    let bytes = bundle().to_bytes();
+    let text = String::from_utf8_lossy(&bytes).replace("\"format\":1", "\"format\":9");
+    assert_eq!(
+        invalid_bundle(text.as_bytes()),
+        "unsupported format version 9"
+    );
+}
+
+#[test]
+fn rejects_an_mcu_listed_twice() {
+    let mut twice = bundle();
+    twice.images[1].target = AP2Target::McuMain;
+
+    assert_eq!(
+        invalid_bundle(&twice.to_bytes()),
+        "McuMain is listed more than once"
+    );
+}
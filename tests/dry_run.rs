@@ -0,0 +1,63 @@
+use annepro2_tools::{
+    dry_run, AP2Target, CancelToken, FlashJob, FlashOptions, FlashPlan, Revision, Segment,
+};
+
+/// Runs a plan that erases one page, writes 100 bytes in three chunks, sets
+/// the AP flag and boots, returning what the dry run printed.
+fn dry_run_output() -> String {
+    let job = FlashJob {
+        target: AP2Target::McuMain,
+        segments: vec![Segment {
+            address: 0x4000,
+            data: vec![0x5a; 100],
+        }],
+    };
+    let mut options = FlashOptions::default();
+    options.boot = true;
+    let plan = FlashPlan::new(&[job], Revision::C15, &options).unwrap();
+    let mut out = Vec::new();
+    let report = dry_run::run(&plan, &options, &(), &CancelToken::new(), &mut out);
+
+    assert!(report.is_success(), "{:?}", report.error);
+    // Only the emulator inside the dry run was booted; no keyboard is opened.
+    assert!(report.booted);
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn prints_every_report_with_what_it_does() {
+    let output = dry_run_output();
+    let lines: Vec<&str> = output.lines().collect();
+    let described: Vec<&str> = lines
+        .iter()
+        .filter(|it| it.starts_with('#'))
+        .map(|it| it[7..].trim_end())
+        .collect();
+
+    assert_eq!(
+        described,
+        [
+            "erase McuMain page at 0x004000",
+            "write 48 bytes to McuMain at 0x004000",
+            "write 48 bytes to McuMain at 0x004030",
+            "write 4 bytes to McuMain at 0x004060",
+            "set AP flag to 2",
+            "boot into the application",
+        ]
+    );
+    assert_eq!(lines.last(), Some(&"6 reports"));
+}
+
+#[test]
+fn prints_full_reports_in_hex() {
+    let output = dry_run_output();
+    let sizes: Vec<usize> = output
+        .lines()
+        .filter(|it| it.starts_with("       "))
+        .map(|it| it.split_whitespace().count())
+        .collect();
+
+    // Every command is padded to a 65 byte report but the mode switch,
+    // which is sent as is.
+    assert_eq!(sizes, [65, 65, 65, 65, 65, 12]);
+}
@@ -0,0 +1,317 @@
+use annepro2_tools::firmware::{self, parse_elf, parse_ihex, Segment};
+use annepro2_tools::{AP2FlashError, AP2Target};
+
+/// One Intel HEX record with a correct checksum.
+fn record(kind: u8, offset: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&offset.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    bytes.push(sum.wrapping_neg());
+    let digits: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    format!(":{}\n", digits)
+}
+
+fn eof() -> String {
+    record(0x01, 0, &[])
+}
+
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PT_ARM_EXIDX: u32 = 0x7000_0001;
+
+/// A program header: type, virtual and physical address, and file contents.
+struct Program {
+    kind: u32,
+    vaddr: u32,
+    paddr: u32,
+    data: Vec<u8>,
+}
+
+/// A minimal 32-bit little-endian ELF file with `programs` as its program
+/// headers, their contents following the headers.
+fn elf(programs: &[Program]) -> Vec<u8> {
+    let mut header = vec![0u8; 52];
+    header[..6].copy_from_slice(b"\x7fELF\x01\x01");
+    header[0x1c..0x20].copy_from_slice(&52u32.to_le_bytes());
+    header[0x2a..0x2c].copy_from_slice(&32u16.to_le_bytes());
+    header[0x2c..0x2e].copy_from_slice(&(programs.len() as u16).to_le_bytes());
+
+    let mut offset = 52 + 32 * programs.len() as u32;
+    let mut contents = Vec::new();
+    for program in programs {
+        let len = program.data.len() as u32;
+        for field in &[
+            program.kind,
+            offset,
+            program.vaddr,
+            program.paddr,
+            len,
+            len,
+            0,
+            4,
+        ] {
+            header.extend_from_slice(&field.to_le_bytes());
+        }
+        contents.extend_from_slice(&program.data);
+        offset += len;
+    }
+    header.extend(contents);
+    header
+}
+
+fn load(paddr: u32, data: &[u8]) -> Program {
+    Program {
+        kind: PT_LOAD,
+        vaddr: paddr,
+        paddr,
+        data: data.to_vec(),
+    }
+}
+
+fn invalid_image(result: Result<Vec<Segment>, AP2FlashError>) -> String {
+    match result {
+        Err(AP2FlashError::InvalidImage(reason)) => reason,
+        other => panic!("expected InvalidImage, got {:?}", other),
+    }
+}
+
+#[test]
+fn merges_contiguous_records_and_keeps_gaps() {
+    let text = [
+        record(0x00, 0x4000, &[1; 16]),
+        record(0x00, 0x4010, &[2; 16]),
+        record(0x00, 0x4100, &[3; 4]),
+        eof(),
+    ]
+    .concat();
+
+    let mut first = vec![1; 16];
+    first.extend_from_slice(&[2; 16]);
+    assert_eq!(
+        parse_ihex(&text).unwrap(),
+        [
+            Segment {
+                address: 0x4000,
+                data: first
+            },
+            Segment {
+                address: 0x4100,
+                data: vec![3; 4]
+            },
+        ]
+    );
+}
+
+#[test]
+fn sorts_records_given_out_of_order() {
+    let text = [
+        record(0x00, 0x4010, &[2; 16]),
+        record(0x00, 0x4000, &[1; 16]),
+        eof(),
+    ]
+    .concat();
+
+    let segments = parse_ihex(&text).unwrap();
+    assert_eq!(segments.len(), 1);
+    assert_eq!(segments[0].address, 0x4000);
+    assert_eq!(segments[0].data.len(), 32);
+}
+
+#[test]
+fn applies_extended_linear_addresses() {
+    let text = [
+        record(0x04, 0, &[0x08, 0x00]),
+        record(0x00, 0x4000, &[0xaa; 8]),
+        eof(),
+    ]
+    .concat();
+
+    let segments = parse_ihex(&text).unwrap();
+    assert_eq!(segments[0].address, 0x0800_4000);
+}
+
+#[test]
+fn applies_extended_segment_addresses() {
+    let text = [
+        record(0x02, 0, &[0x10, 0x00]),
+        record(0x00, 0x0020, &[0xaa; 8]),
+        eof(),
+    ]
+    .concat();
+
+    let segments = parse_ihex(&text).unwrap();
+    assert_eq!(segments[0].address, 0x10020);
+}
+
+#[test]
+fn ignores_start_addresses_and_anything_after_end_of_file() {
+    let text = [
+        record(0x03, 0, &[0, 0, 0x40, 0]),
+        record(0x05, 0, &[0, 0, 0x41, 0x01]),
+        record(0x00, 0x4000, &[0xaa; 8]),
+        eof(),
+        record(0x00, 0x5000, &[0xbb; 8]),
+    ]
+    .concat();
+
+    let segments = parse_ihex(&text).unwrap();
+    assert_eq!(segments.len(), 1);
+    assert_eq!(segments[0].address, 0x4000);
+}
+
+#[test]
+fn rejects_a_bad_checksum() {
+    let mut bad = record(0x00, 0x4008, &[0xaa; 8]);
+    bad.replace_range(bad.len() - 3..bad.len() - 1, "00");
+    let text = [record(0x00, 0x4000, &[0xaa; 8]), bad, eof()].concat();
+
+    assert_eq!(invalid_image(parse_ihex(&text)), "HEX line 2: bad checksum");
+}
+
+#[test]
+fn rejects_overlapping_records() {
+    let text = [
+        record(0x00, 0x4000, &[0xaa; 16]),
+        record(0x00, 0x4008, &[0xbb; 16]),
+        eof(),
+    ]
+    .concat();
+
+    assert!(invalid_image(parse_ihex(&text)).contains("overlaps"));
+}
+
+#[test]
+fn rejects_malformed_records() {
+    let missing_colon = record(0x00, 0x4000, &[0xaa; 4]).replace(':', "");
+    assert!(invalid_image(parse_ihex(&missing_colon)).contains("missing ':'"));
+
+    let short = [&record(0x00, 0x4000, &[0xaa; 4])[..9], "\n", &eof()].concat();
+    assert!(invalid_image(parse_ihex(&short)).contains("record length mismatch"));
+
+    let wrong_length = record(0x04, 0, &[0x08]);
+    assert!(invalid_image(parse_ihex(&wrong_length)).contains("record length mismatch"));
+
+    let unknown = record(0x06, 0, &[]);
+    assert!(invalid_image(parse_ihex(&unknown)).contains("unknown record type 0x06"));
+}
+
+#[test]
+fn places_loadable_segments_at_their_physical_address() {
+    let data = Program {
+        // .data runs from RAM but is stored in flash right after .text.
+        vaddr: 0x2000_0000,
+        ..load(0x4100, &[2; 8])
+    };
+    let bytes = elf(&[load(0x4000, &[1; 0x100]), data]);
+
+    let mut image = vec![1; 0x100];
+    image.extend_from_slice(&[2; 8]);
+    assert_eq!(
+        parse_elf(&bytes).unwrap(),
+        [Segment {
+            address: 0x4000,
+            data: image
+        }]
+    );
+}
+
+#[test]
+fn skips_segments_that_are_not_loaded() {
+    let note = Program {
+        kind: PT_NOTE,
+        ..load(0, &[9; 16])
+    };
+    let exidx = Program {
+        kind: PT_ARM_EXIDX,
+        ..load(0x4200, &[8; 8])
+    };
+    let bss = load(0x5000, &[]);
+    let bytes = elf(&[note, load(0x4000, &[1; 16]), exidx, bss]);
+
+    assert_eq!(
+        parse_elf(&bytes).unwrap(),
+        [Segment {
+            address: 0x4000,
+            data: vec![1; 16]
+        }]
+    );
+}
+
+#[test]
+fn rejects_truncated_files() {
+    let bytes = elf(&[load(0x4000, &[1; 16])]);
+
+    assert_eq!(
+        invalid_image(parse_elf(&bytes[..0x20])),
+        "ELF: truncated header"
+    );
+    assert_eq!(
+        invalid_image(parse_elf(&bytes[..60])),
+        "ELF: truncated program header"
+    );
+    assert_eq!(
+        invalid_image(parse_elf(&bytes[..bytes.len() - 1])),
+        "ELF: segment data past end of file"
+    );
+}
+
+#[test]
+fn rejects_offsets_near_the_end_of_the_address_space() {
+    let mut bytes = elf(&[load(0x4000, &[1; 16])]);
+    bytes[0x1c..0x20].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(
+        invalid_image(parse_elf(&bytes)),
+        "ELF: truncated program header"
+    );
+
+    // The file offset of the only segment, then its size.
+    let mut bytes = elf(&[load(0x4000, &[1; 16])]);
+    bytes[56..60].copy_from_slice(&0xffff_fff8u32.to_le_bytes());
+    bytes[68..72].copy_from_slice(&0x10u32.to_le_bytes());
+    assert_eq!(
+        invalid_image(parse_elf(&bytes)),
+        "ELF: segment data past end of file"
+    );
+}
+
+#[test]
+fn rejects_other_classes_and_byte_orders() {
+    let mut bytes = elf(&[load(0x4000, &[1; 16])]);
+    bytes[4] = 2;
+    assert!(invalid_image(parse_elf(&bytes)).contains("32-bit little-endian"));
+
+    let mut bytes = elf(&[load(0x4000, &[1; 16])]);
+    bytes[5] = 2;
+    assert!(invalid_image(parse_elf(&bytes)).contains("32-bit little-endian"));
+
+    assert_eq!(invalid_image(parse_elf(b"MZ\x90\x00")), "ELF: bad magic");
+}
+
+#[test]
+fn rejects_overlapping_segments() {
+    let bytes = elf(&[load(0x4000, &[1; 16]), load(0x4008, &[2; 16])]);
+
+    assert!(invalid_image(parse_elf(&bytes)).contains("overlaps"));
+}
+
+#[test]
+fn rejects_data_past_the_end_of_the_address_space() {
+    let segment = Segment {
+        address: 0xffff_ff00,
+        data: vec![0x5a; 512],
+    };
+    assert_eq!(segment.end(), None);
+
+    let reason = invalid_image(firmware::normalize(vec![segment.clone()]));
+    assert!(reason.contains("past the end of the address space"));
+    let result = firmware::check_fits(&[segment], AP2Target::McuMain, None);
+    assert!(matches!(result, Err(AP2FlashError::InvalidImage(_))));
+
+    let path = std::env::temp_dir().join(format!("annepro2-overflow-{}.bin", std::process::id()));
+    std::fs::write(&path, [0x5a; 512]).unwrap();
+    let result = firmware::load(&path, 0xffff_ff00);
+    std::fs::remove_file(&path).unwrap();
+    assert!(invalid_image(result).contains("past the end of the address space"));
+}
@@ -0,0 +1,404 @@
+use annepro2_tools::memory_map::MemoryMap;
+use annepro2_tools::testing::Emulator;
+use annepro2_tools::{
+    flash_session, AP2FlashError, AP2Target, BatchReport, CancelToken, FlashEvent, FlashJob,
+    FlashOptions, RetryPolicy, Revision, Segment, Transport,
+};
+use hidapi::{HidError, HidResult};
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+/// An emulator with the flash sizes of a `revision` keyboard.
+fn keyboard(revision: Revision) -> Emulator {
+    let mut emulator = Emulator::new();
+    for &target in &[AP2Target::McuMain, AP2Target::McuLed, AP2Target::McuBle] {
+        if let Some(map) = MemoryMap::of(target, revision) {
+            emulator = emulator.with_flash_size(target, map.flash_size as usize);
+        }
+    }
+    emulator
+}
+
+fn image(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+}
+
+fn flash<T: Transport>(handle: &T, jobs: &[FlashJob], options: &FlashOptions) -> BatchReport {
+    flash_session(
+        handle,
+        Revision::C15,
+        jobs,
+        options,
+        &(),
+        &CancelToken::new(),
+    )
+}
+
+fn job(target: AP2Target, address: u32, data: &[u8]) -> FlashJob {
+    FlashJob {
+        target,
+        segments: vec![Segment {
+            address,
+            data: data.to_vec(),
+        }],
+    }
+}
+
+/// Fails the first `failures` chunk writes before they reach `inner`.
+struct Flaky {
+    inner: Emulator,
+    failures: Cell<u32>,
+}
+
+impl Transport for Flaky {
+    fn write_report(&self, data: &[u8]) -> HidResult<usize> {
+        // Report id, 8 byte header, then the command class and the command.
+        if data.get(10) == Some(&0x31) && self.failures.get() > 0 {
+            self.failures.set(self.failures.get() - 1);
+            return Err(HidError::HidApiError {
+                message: "write timed out".into(),
+            });
+        }
+        self.inner.write_report(data)
+    }
+
+    fn read_report(&self, buf: &mut [u8], timeout_ms: i32) -> HidResult<usize> {
+        self.inner.read_report(buf, timeout_ms)
+    }
+}
+
+fn quick_retries(retries: u32) -> FlashOptions {
+    let mut options = FlashOptions::default();
+    options.retry = RetryPolicy {
+        retries,
+        backoff: Duration::from_millis(1),
+    };
+    options
+}
+
+#[test]
+fn flashes_every_image_byte_for_byte() {
+    let emulator = keyboard(Revision::C15);
+    let main_low = image(1000);
+    let main_high = image(77);
+    let led = image(4096);
+    let jobs = [
+        FlashJob {
+            target: AP2Target::McuMain,
+            segments: vec![
+                Segment {
+                    address: 0x4000,
+                    data: main_low.clone(),
+                },
+                Segment {
+                    address: 0x6010,
+                    data: main_high.clone(),
+                },
+            ],
+        },
+        job(AP2Target::McuLed, 0x4000, &led),
+    ];
+    let mut options = FlashOptions::default();
+    options.verify = true;
+    let report = flash(&emulator, &jobs, &options);
+
+    assert!(report.is_success(), "{:?}", report.error);
+    let mut expected = vec![0xffu8; 0x10000];
+    expected[0x4000..0x4000 + main_low.len()].copy_from_slice(&main_low);
+    expected[0x6010..0x6010 + main_high.len()].copy_from_slice(&main_high);
+    assert_eq!(emulator.flash(AP2Target::McuMain), expected);
+    let mut expected = vec![0xffu8; 0x10000];
+    expected[0x4000..0x4000 + led.len()].copy_from_slice(&led);
+    assert_eq!(emulator.flash(AP2Target::McuLed), expected);
+    assert_eq!(emulator.flash(AP2Target::McuBle), vec![0xffu8; 0x10000]);
+
+    let targets: Vec<_> = report.targets.iter().map(|it| it.target).collect();
+    assert_eq!(targets, [AP2Target::McuMain, AP2Target::McuLed]);
+    assert!(report.targets.iter().all(|it| it.verified));
+    assert_eq!(report.targets[0].bytes, main_low.len() + main_high.len());
+}
+
+#[test]
+fn erases_every_page_of_segments_given_out_of_order() {
+    let emulator = keyboard(Revision::C15);
+    let old = image(0x400);
+    let report = flash(
+        &emulator,
+        &[job(AP2Target::McuMain, 0x4000, &old)],
+        &FlashOptions::default(),
+    );
+    assert!(report.is_success(), "{:?}", report.error);
+
+    let new: Vec<u8> = old.iter().map(|b| !b).collect();
+    let jobs = [FlashJob {
+        target: AP2Target::McuMain,
+        segments: vec![
+            Segment {
+                address: 0x4800,
+                data: image(0x10),
+            },
+            Segment {
+                address: 0x4000,
+                data: new.clone(),
+            },
+        ],
+    }];
+    let report = flash(&emulator, &jobs, &FlashOptions::default());
+
+    assert!(report.is_success(), "{:?}", report.error);
+    let flash = emulator.flash(AP2Target::McuMain);
+    assert_eq!(&flash[0x4000..0x4400], &new[..]);
+    assert_eq!(&flash[0x4800..0x4810], &image(0x10)[..]);
+}
+
+#[test]
+fn full_erase_clears_the_whole_application_region() {
+    let emulator = keyboard(Revision::C15);
+    let report = flash(
+        &emulator,
+        &[job(AP2Target::McuMain, 0x8000, &image(0x1000))],
+        &FlashOptions::default(),
+    );
+    assert!(report.is_success(), "{:?}", report.error);
+
+    let data = image(100);
+    let mut options = FlashOptions::default();
+    options.full_erase = true;
+    let report = flash(
+        &emulator,
+        &[job(AP2Target::McuMain, 0x4000, &data)],
+        &options,
+    );
+
+    assert!(report.is_success(), "{:?}", report.error);
+    let mut expected = vec![0xffu8; 0x10000];
+    expected[0x4000..0x4000 + data.len()].copy_from_slice(&data);
+    assert_eq!(emulator.flash(AP2Target::McuMain), expected);
+}
+
+#[test]
+fn sets_the_ap_flag_and_only_boots_when_asked() {
+    let emulator = keyboard(Revision::C15);
+    let report = flash(
+        &emulator,
+        &[job(AP2Target::McuMain, 0x4000, &image(100))],
+        &FlashOptions::default(),
+    );
+    assert!(report.is_success(), "{:?}", report.error);
+    assert!(report.ap_flag_written);
+    assert!(!report.booted);
+    assert_eq!(emulator.ap_flag(), Some(2));
+    assert!(!emulator.booted());
+
+    let emulator = keyboard(Revision::C15);
+    let mut options = FlashOptions::default();
+    options.boot = true;
+    let report = flash(
+        &emulator,
+        &[job(AP2Target::McuMain, 0x4000, &image(100))],
+        &options,
+    );
+    assert!(report.is_success(), "{:?}", report.error);
+    assert!(report.booted);
+    assert_eq!(emulator.ap_flag(), Some(2));
+    assert!(emulator.booted());
+}
+
+#[test]
+fn retries_a_failed_chunk_write() {
+    let transport = Flaky {
+        inner: keyboard(Revision::C15),
+        failures: Cell::new(2),
+    };
+    let data = image(500);
+    let retries = RefCell::new(Vec::new());
+    let observer = |event: &FlashEvent| {
+        if let FlashEvent::Retry {
+            address, attempt, ..
+        } = event
+        {
+            retries.borrow_mut().push((*address, *attempt));
+        }
+    };
+    let report = flash_session(
+        &transport,
+        Revision::C15,
+        &[job(AP2Target::McuMain, 0x4000, &data)],
+        &quick_retries(3),
+        &observer,
+        &CancelToken::new(),
+    );
+
+    assert!(report.is_success(), "{:?}", report.error);
+    assert_eq!(*retries.borrow(), [(0x4000, 1), (0x4000, 2)]);
+    let flash = transport.inner.flash(AP2Target::McuMain);
+    assert_eq!(&flash[0x4000..0x4000 + data.len()], &data[..]);
+}
+
+#[test]
+fn gives_up_once_retries_are_exhausted() {
+    let transport = Flaky {
+        inner: keyboard(Revision::C15),
+        failures: Cell::new(u32::MAX),
+    };
+    let report = flash(
+        &transport,
+        &[job(AP2Target::McuMain, 0x4000, &image(500))],
+        &quick_retries(2),
+    );
+
+    match report.error {
+        Some(AP2FlashError::WriteFailed {
+            address, attempts, ..
+        }) => {
+            assert_eq!(address, 0x4000);
+            assert_eq!(attempts, 3);
+        }
+        ref other => panic!("expected WriteFailed, got {:?}", other),
+    }
+    assert!(report.needs_reflash());
+    assert_eq!(transport.inner.ap_flag(), None);
+}
+
+#[test]
+fn cancelling_stops_before_the_next_chunk() {
+    let emulator = keyboard(Revision::C15);
+    let data = image(500);
+    let cancel = CancelToken::new();
+    let chunks = RefCell::new(Vec::new());
+    let observer = |event: &FlashEvent| {
+        if let FlashEvent::ChunkWritten { address, len, .. } = event {
+            chunks.borrow_mut().push((*address, *len));
+            cancel.cancel();
+        }
+    };
+    let report = flash_session(
+        &emulator,
+        Revision::C15,
+        &[job(AP2Target::McuMain, 0x4000, &data)],
+        &FlashOptions::default(),
+        &observer,
+        &cancel,
+    );
+
+    assert!(matches!(report.error, Some(AP2FlashError::Cancelled)));
+    assert_eq!(emulator.ap_flag(), None);
+    assert!(report.needs_reflash());
+    assert_eq!(*chunks.borrow(), [(0x4000, 48)]);
+    let flash = emulator.flash(AP2Target::McuMain);
+    assert_eq!(&flash[0x4000..0x4030], &data[..48]);
+    assert!(flash[0x4030..].iter().all(|&b| b == 0xff));
+}
+
+#[test]
+fn rejected_erase_stops_the_session() {
+    // Pages of 0x800 bytes make the erase of the 0x400 page at 0x4400
+    // unaligned, which the bootloader refuses.
+    let emulator = keyboard(Revision::C15).with_page_size(0x800);
+    let report = flash(
+        &emulator,
+        &[job(AP2Target::McuMain, 0x4400, &image(100))],
+        &FlashOptions::default(),
+    );
+
+    match report.error {
+        Some(AP2FlashError::Rejected {
+            target, address, ..
+        }) => {
+            assert_eq!(target, AP2Target::McuMain);
+            assert_eq!(address, Some(0x4400));
+        }
+        ref other => panic!("expected Rejected, got {:?}", other),
+    }
+    assert!(report.targets.is_empty());
+    assert!(report.needs_reflash());
+    assert_eq!(emulator.flash(AP2Target::McuMain), vec![0xffu8; 0x10000]);
+    assert_eq!(emulator.ap_flag(), None);
+}
+
+#[test]
+fn image_past_the_end_of_flash_is_refused_before_anything_is_sent() {
+    let emulator = keyboard(Revision::C15);
+    let report = flash(
+        &emulator,
+        &[job(AP2Target::McuMain, 0xff00, &image(0x200))],
+        &FlashOptions::default(),
+    );
+
+    match report.error {
+        Some(AP2FlashError::OutsideApplicationRegion { start, end, .. }) => {
+            assert_eq!(start..end, 0xff00..0x10100)
+        }
+        ref other => panic!("expected OutsideApplicationRegion, got {:?}", other),
+    }
+    assert!(!report.flash_modified);
+    assert_eq!(emulator.flash(AP2Target::McuMain), vec![0xffu8; 0x10000]);
+    assert_eq!(emulator.ap_flag(), None);
+}
+
+#[test]
+fn last_chunk_ending_near_the_end_of_flash_is_not_padded_past_it() {
+    // 49116 bytes at 0x4010 leave a 12 byte last chunk at 0xffe0; padded to
+    // a full 48 byte chunk it would run to 0x10010, past the 64 KB of the
+    // main MCU.
+    let emulator = keyboard(Revision::C15);
+    let data = image(49116);
+    let report = flash(
+        &emulator,
+        &[job(AP2Target::McuMain, 0x4010, &data)],
+        &FlashOptions::default(),
+    );
+
+    assert!(report.is_success(), "{:?}", report.error);
+    let flash = emulator.flash(AP2Target::McuMain);
+    assert_eq!(&flash[0x4010..0x4010 + data.len()], &data[..]);
+    assert_eq!(&flash[0x4010 + data.len()..], &[0xff; 20][..]);
+}
+
+#[test]
+fn verifying_fails_clearly_without_read_back_or_checksums() {
+    let emulator = keyboard(Revision::C15)
+        .with_read_memory(false)
+        .with_checksum(false);
+    let mut options = FlashOptions::default();
+    options.verify = true;
+    let report = flash(
+        &emulator,
+        &[job(AP2Target::McuMain, 0x4000, &image(100))],
+        &options,
+    );
+
+    match report.error {
+        Some(AP2FlashError::VerifyUnsupported { target, .. }) => {
+            assert_eq!(target, AP2Target::McuMain)
+        }
+        other => panic!("expected VerifyUnsupported, got {:?}", other),
+    }
+    assert!(!report.ap_flag_written);
+}
+
+/// Answers every command with the same report, which echoes nothing back.
+struct Unrecognised;
+
+impl Transport for Unrecognised {
+    fn write_report(&self, data: &[u8]) -> HidResult<usize> {
+        Ok(data.len())
+    }
+
+    fn read_report(&self, buf: &mut [u8], _timeout_ms: i32) -> HidResult<usize> {
+        buf.iter_mut().for_each(|b| *b = 0x5a);
+        Ok(buf.len())
+    }
+}
+
+#[test]
+fn unrecognised_replies_do_not_fail_the_session() {
+    let report = flash(
+        &Unrecognised,
+        &[job(AP2Target::McuMain, 0x4000, &image(100))],
+        &FlashOptions::default(),
+    );
+
+    assert!(report.is_success(), "{:?}", report.error);
+    assert!(report.ap_flag_written);
+}
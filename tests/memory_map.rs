@@ -0,0 +1,40 @@
+use annepro2_tools::memory_map::MemoryMap;
+use annepro2_tools::{AP2Target, Revision, Segment};
+
+fn segment(address: u32, len: usize) -> Segment {
+    Segment {
+        address,
+        data: vec![0x5a; len],
+    }
+}
+
+fn main_map() -> MemoryMap {
+    MemoryMap::of(AP2Target::McuMain, Revision::C15).unwrap()
+}
+
+#[test]
+fn covers_whole_pages_and_merges_neighbours() {
+    let pages = main_map().pages_covering(&[
+        segment(0x4010, 0x10),
+        segment(0x4300, 0x200),
+        segment(0x4800, 0x400),
+        segment(0x6000, 1),
+    ]);
+
+    assert_eq!(pages, [0x4000..0x4c00, 0x6000..0x6400]);
+}
+
+#[test]
+fn covers_segments_given_out_of_order() {
+    let pages = main_map().pages_covering(&[segment(0x4800, 0x10), segment(0x4000, 0x400)]);
+
+    assert_eq!(pages, [0x4000..0x4400, 0x4800..0x4c00]);
+}
+
+#[test]
+fn skips_empty_segments() {
+    let pages = main_map().pages_covering(&[segment(0x5000, 0), segment(0x4000, 1)]);
+
+    assert_eq!(pages.len(), 1);
+    assert_eq!(pages[0], 0x4000..0x4400);
+}
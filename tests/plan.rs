@@ -0,0 +1,267 @@
+use annepro2_tools::plan::Step;
+use annepro2_tools::{
+    AP2FlashError, AP2Target, FlashJob, FlashOptions, FlashPlan, Revision, Segment,
+};
+
+const MAIN: AP2Target = AP2Target::McuMain;
+
+/// Erase 0x4000..0x4400, three writes covering 100 bytes, a read-back, the
+/// AP flag and the boot.
+fn plan() -> FlashPlan {
+    let job = FlashJob {
+        target: MAIN,
+        segments: vec![Segment {
+            address: 0x4000,
+            data: vec![0x5a; 100],
+        }],
+    };
+    let mut options = FlashOptions::default();
+    options.verify = true;
+    options.boot = true;
+    FlashPlan::new(&[job], Revision::C15, &options).unwrap()
+}
+
+fn write(address: u32, len: usize) -> Step {
+    Step::Write {
+        target: MAIN,
+        address,
+        data: "5a".repeat(len),
+    }
+}
+
+fn erase(start: u32, end: u32) -> Step {
+    Step::Erase {
+        target: MAIN,
+        range: start..end,
+    }
+}
+
+fn position(plan: &FlashPlan, step: &Step) -> usize {
+    plan.steps.iter().position(|it| it == step).unwrap()
+}
+
+/// Validates `plan` after `change`, returning why it was rejected.
+fn rejection(change: impl FnOnce(&mut FlashPlan)) -> AP2FlashError {
+    let mut plan = plan();
+    change(&mut plan);
+    plan.validate().expect_err("plan should be rejected")
+}
+
+fn reason(change: impl FnOnce(&mut FlashPlan)) -> String {
+    match rejection(change) {
+        AP2FlashError::InvalidPlan(reason) => reason,
+        other => panic!("expected InvalidPlan, got {:?}", other),
+    }
+}
+
+#[test]
+fn planned_sessions_are_valid() {
+    let plan = plan();
+    assert_eq!(
+        plan.steps,
+        [
+            erase(0x4000, 0x4400),
+            write(0x4000, 48),
+            write(0x4030, 48),
+            write(0x4060, 4),
+            Step::Verify {
+                target: MAIN,
+                address: 0x4000,
+                len: 100
+            },
+            Step::ApFlag { flag: 2 },
+            Step::Boot,
+        ]
+    );
+    plan.validate().unwrap();
+    assert_eq!(FlashPlan::parse(&plan.to_json()).unwrap(), plan);
+}
+
+#[test]
+fn rejects_steps_after_booting() {
+    assert_eq!(
+        reason(|plan| plan.steps.push(Step::Boot)),
+        "step 8: nothing can follow booting"
+    );
+}
+
+#[test]
+fn rejects_changing_flash_after_the_ap_flag() {
+    assert_eq!(
+        reason(|plan| plan.steps.insert(6, write(0x4064, 4))),
+        "step 7: flash is changed after the AP flag was written"
+    );
+}
+
+#[test]
+fn rejects_partial_pages() {
+    assert_eq!(
+        reason(|plan| plan.steps[0] = erase(0x4000, 0x4100)),
+        "step 1: erase range is not made of whole pages"
+    );
+}
+
+#[test]
+fn rejects_erasing_the_bootloader() {
+    match rejection(|plan| plan.steps.insert(0, erase(0x3c00, 0x4000))) {
+        AP2FlashError::OutsideApplicationRegion { start, end, .. } => {
+            assert_eq!(start..end, 0x3c00..0x4000)
+        }
+        other => panic!("expected OutsideApplicationRegion, got {:?}", other),
+    }
+}
+
+#[test]
+fn rejects_erasing_written_data() {
+    assert_eq!(
+        reason(|plan| plan.steps.insert(2, erase(0x4000, 0x4400))),
+        "step 3: erases data written by an earlier step"
+    );
+}
+
+#[test]
+fn rejects_bad_chunks() {
+    assert_eq!(
+        reason(|plan| plan.steps[1] = Step::Write {
+            target: MAIN,
+            address: 0x4000,
+            data: "zz".into(),
+        }),
+        "step 2: chunk data is not valid hex"
+    );
+    assert_eq!(
+        reason(|plan| plan.steps[1] = write(0x4000, 0)),
+        "step 2: chunk data is not valid hex"
+    );
+    assert_eq!(
+        reason(|plan| plan.steps[1] = write(0x4000, 49)),
+        "step 2: chunk is larger than one write command"
+    );
+}
+
+#[test]
+fn rejects_writes_outside_the_application_region() {
+    match rejection(|plan| plan.steps.insert(1, write(0x3ff0, 16))) {
+        AP2FlashError::BootloaderOverlap { address } => assert_eq!(address, 0x3ff0),
+        other => panic!("expected BootloaderOverlap, got {:?}", other),
+    }
+    match rejection(|plan| plan.steps.insert(1, write(0xffe0, 48))) {
+        AP2FlashError::OutsideApplicationRegion { start, end, .. } => {
+            assert_eq!(start..end, 0xffe0..0x10010)
+        }
+        other => panic!("expected OutsideApplicationRegion, got {:?}", other),
+    }
+}
+
+#[test]
+fn refuses_to_plan_data_past_the_end_of_the_address_space() {
+    let job = FlashJob {
+        target: MAIN,
+        segments: vec![Segment {
+            address: 0xffff_ff00,
+            data: vec![0x5a; 512],
+        }],
+    };
+    let result = FlashPlan::new(&[job], Revision::C15, &FlashOptions::default());
+    assert!(matches!(result, Err(AP2FlashError::InvalidImage(_))));
+    assert!(reason(|plan| plan.steps.insert(1, write(0xffff_fff0, 32))).contains("address space"));
+}
+
+#[test]
+fn rejects_writing_unerased_flash() {
+    assert_eq!(
+        reason(|plan| plan.steps.insert(4, write(0x4400, 4))),
+        "step 5: writes to flash that was not erased"
+    );
+}
+
+#[test]
+fn rejects_writing_a_chunk_twice() {
+    assert_eq!(
+        reason(|plan| plan.steps.insert(2, write(0x4010, 4))),
+        "step 3: overwrites data written by an earlier step"
+    );
+}
+
+#[test]
+fn rejects_reading_back_unwritten_data() {
+    assert_eq!(
+        reason(|plan| plan.steps[4] = Step::Verify {
+            target: MAIN,
+            address: 0x4000,
+            len: 200,
+        }),
+        "step 5: reads back data the plan did not write"
+    );
+    assert_eq!(
+        reason(|plan| plan.steps[4] = Step::Verify {
+            target: MAIN,
+            address: 0x4000,
+            len: 0,
+        }),
+        "step 5: reads back data the plan did not write"
+    );
+}
+
+#[test]
+fn rejects_writing_the_ap_flag_twice() {
+    assert_eq!(
+        reason(|plan| plan.steps.insert(6, Step::ApFlag { flag: 2 })),
+        "step 7: the AP flag is written twice"
+    );
+}
+
+#[test]
+fn rejects_booting_without_the_ap_flag() {
+    assert_eq!(
+        reason(|plan| {
+            let index = position(plan, &Step::ApFlag { flag: 2 });
+            plan.steps.remove(index);
+        }),
+        "step 6: booting before the AP flag is written"
+    );
+}
+
+#[test]
+fn rejects_plans_that_write_nothing() {
+    assert_eq!(
+        reason(|plan| plan.steps = vec![erase(0x4000, 0x4400), Step::ApFlag { flag: 2 }]),
+        "nothing is written"
+    );
+}
+
+#[test]
+fn rejects_plans_that_never_write_the_ap_flag() {
+    assert_eq!(
+        reason(|plan| plan.steps.truncate(5)),
+        "the AP flag is never written"
+    );
+}
+
+#[test]
+fn checks_regions_against_the_revision_of_the_plan() {
+    // The LED MCU has 128 KB on C18 but only 64 KB on C15.
+    let led = AP2Target::McuLed;
+    let mut plan = FlashPlan {
+        revision: Revision::C18,
+        steps: vec![
+            Step::Erase {
+                target: led,
+                range: 0x1fc00..0x20000,
+            },
+            Step::Write {
+                target: led,
+                address: 0x1fc00,
+                data: "5a5a5a5a".into(),
+            },
+            Step::ApFlag { flag: 2 },
+        ],
+    };
+    plan.validate().unwrap();
+
+    plan.revision = Revision::C15;
+    match plan.validate() {
+        Err(AP2FlashError::OutsideApplicationRegion { target, .. }) => assert_eq!(target, led),
+        other => panic!("expected OutsideApplicationRegion, got {:?}", other),
+    }
+}
@@ -0,0 +1,91 @@
+use annepro2_tools::memory_map::MemoryMap;
+use annepro2_tools::record::{Recorder, Replay};
+use annepro2_tools::testing::Emulator;
+use annepro2_tools::{
+    flash_session, AP2FlashError, AP2Target, BatchReport, CancelToken, FlashJob, FlashOptions,
+    RetryPolicy, Revision, Segment, Transport,
+};
+
+fn flash<T: Transport>(handle: &T, data: &[u8]) -> BatchReport {
+    let job = FlashJob {
+        target: AP2Target::McuLed,
+        segments: vec![Segment {
+            address: 0x4000,
+            data: data.to_vec(),
+        }],
+    };
+    let mut options = FlashOptions::default();
+    options.verify = true;
+    options.boot = true;
+    options.retry = RetryPolicy {
+        retries: 0,
+        ..RetryPolicy::default()
+    };
+    flash_session(
+        handle,
+        Revision::C15,
+        &[job],
+        &options,
+        &(),
+        &CancelToken::new(),
+    )
+}
+
+/// Flashes `data` onto an emulator and returns the recording of it.
+fn record(data: &[u8]) -> String {
+    let map = MemoryMap::of(AP2Target::McuLed, Revision::C15).unwrap();
+    let emulator = Emulator::new().with_flash_size(AP2Target::McuLed, map.flash_size as usize);
+    let recorder = Recorder::new(emulator, Vec::new());
+    let report = flash(&recorder, data);
+    assert!(report.is_success(), "{:?}", report.error);
+    String::from_utf8(recorder.into_output()).unwrap()
+}
+
+fn image() -> Vec<u8> {
+    (0..200).map(|i| (i * 13) as u8).collect()
+}
+
+#[test]
+fn replays_a_recorded_session() {
+    let recording = record(&image());
+    assert!(recording.lines().count() > 0);
+
+    let replay = Replay::parse(&recording).unwrap();
+    let report = flash(&replay, &image());
+
+    assert!(report.is_success(), "{:?}", report.error);
+    assert!(report.ap_flag_written);
+    assert!(report.booted);
+    assert!(replay.is_finished());
+}
+
+#[test]
+fn replay_rejects_a_write_that_differs_from_the_recording() {
+    let recording = record(&image());
+    let mut changed = image();
+    changed[60] ^= 0xff;
+
+    let replay = Replay::parse(&recording).unwrap();
+    let report = flash(&replay, &changed);
+
+    match &report.error {
+        Some(AP2FlashError::WriteFailed { address, .. }) => assert_eq!(*address, 0x4030),
+        other => panic!("expected WriteFailed, got {:?}", other),
+    }
+    let message = report.error.unwrap().to_string();
+    assert!(
+        message.contains("differs from the recording"),
+        "{}",
+        message
+    );
+    assert!(!report.ap_flag_written);
+}
+
+#[test]
+fn malformed_recording_is_refused() {
+    match Replay::parse("{\"elapsed_us\":0}\n") {
+        Err(AP2FlashError::InvalidRecording(reason)) => assert!(reason.starts_with("line 1")),
+        Err(err) => panic!("expected InvalidRecording, got {:?}", err),
+        Ok(_) => panic!("expected InvalidRecording"),
+    }
+}